@@ -1,32 +1,27 @@
 //! Simple profiler that runs tests the `search` query in the nekop2p RPC.
 //!
 //! The compiled binary uses `-c` for the number of concurrent clients and `-n` for the number of
-//! requests to run and simulates `c*n` search queries on a dummy [IndexerServer].
+//! requests to run and simulates `c*n` search queries on dummy [IndexerNode]s.
 //!
 //! Additionally, plots can be generated using the [plotly] crate.
-use std::iter::repeat;
+use std::iter::repeat_n;
 use std::net::ToSocketAddrs;
-use std::time::Instant;
+use std::time::{Instant, SystemTime};
 use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
-use dashmap::DashMap;
-use delay_map::HashSetDelay;
-use futures::{future, prelude::*};
+use futures::future;
 use plotly::common::Mode;
 use plotly::Histogram;
 use plotly::{layout::Axis, Layout, Plot, Scatter};
-use tarpc::{
-    client, context,
-    serde_transport::tcp,
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Bincode,
-};
+use tarpc::{client, context};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use nekop2p::{Indexer, IndexerClient, IndexerServer};
+use nekop2p::{
+    handshake, Endpoint, Features, FileRecord, IndexerClient, IndexerNode, Keypair, Query, Security,
+};
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
@@ -58,16 +53,25 @@ struct Args {
     /// Uuid backtrace expiration
     #[arg(short, long, default_value_t = 10)]
     b_ttl: u64,
+
+    /// Whether or not to encrypt connections with Noise
+    #[arg(short, long, action)]
+    encrypt: bool,
 }
 
-/// Sets-up an [IndexerServer], with [Args::concurrent] clients and runs [Args::num_requests]
-/// rounds, optionally plotting if [Args::plot] is set
+/// Sets-up [Args::indexers] [IndexerNode]s, with [Args::concurrent] clients and runs
+/// [Args::num_requests] rounds, optionally plotting if [Args::plot] is set
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
     println!("Welcome to the nekop2p profiler!");
     println!("Starting {0} indexers...", args.indexers);
+    let security = if args.encrypt {
+        Security::Noise
+    } else {
+        Security::Plaintext
+    };
 
     // Start indexers here
     let indexers: Vec<_> = (0..args.indexers)
@@ -80,41 +84,18 @@ async fn main() -> Result<()> {
         })
         .collect();
 
+    let mut nodes = Vec::new();
     for i in 0..args.indexers {
-        let index = Arc::new(DashMap::new());
-        let dl_ports = Arc::new(DashMap::new());
         let mut neighbors = indexers.clone();
         neighbors.swap_remove(i);
-        let neighbors = Arc::new(neighbors);
-        let backtrace = Arc::new(RwLock::new(HashSetDelay::new(Duration::from_secs(
-            args.b_ttl,
-        ))));
-        let listener =
-            tcp::listen(("127.0.0.1", args.start_port + i as u16), Bincode::default).await?;
-        tokio::spawn(
-            listener
-                // Ignore accept errors.
-                .filter_map(|r| future::ready(r.ok()))
-                // Establish serve channel
-                .map(BaseChannel::with_defaults)
-                .map(move |channel| {
-                    let server = IndexerServer::new(
-                        channel.transport().peer_addr().unwrap(),
-                        &index,
-                        &dl_ports,
-                        &neighbors,
-                        &backtrace,
-                    );
-                    channel
-                        .execute(server.serve())
-                        .for_each(|response| async move {
-                            tokio::spawn(response);
-                        })
-                })
-                // Max 10 channels.
-                .buffer_unordered(10)
-                .for_each(|_| async {}),
-        );
+        let node = IndexerNode::builder()
+            .bind(indexers[i])
+            .neighbors(neighbors)
+            .backtrace_ttl(Duration::from_secs(args.b_ttl))
+            .security(security)
+            .start()
+            .await?;
+        nodes.push(node);
     }
 
     // Begin profiling requests
@@ -122,28 +103,42 @@ async fn main() -> Result<()> {
     println!("Spawning {0} clients", args.concurrent);
     let mut clients = Vec::new();
     for host in indexers.iter().cycle().take(args.concurrent) {
-        let transport = tcp::connect(host, Bincode::default);
-        let client = IndexerClient::new(client::Config::default(), transport.await?).spawn();
-        clients.push(client);
+        let endpoint = Endpoint::new(&Arc::new(Keypair::generate()), security);
+        let transport = endpoint.connect(*host, None).await?;
+        let client = IndexerClient::new(client::Config::default(), transport).spawn();
+        clients.push((client, endpoint));
     }
 
-    // Register binary files on the first peer
-    for (i, c) in (1..=10).cycle().zip(clients.iter()) {
+    // Register binary files on the first peer, introducing each client with its identity and a
+    // dummy download port first so the indexer accepts its registrations
+    for (port, (i, (c, endpoint))) in (1..).zip((1..=10).cycle().zip(clients.iter())) {
+        let keypair = endpoint.keypair();
+        handshake(c, keypair, Features::NONE).await?;
+        c.set_port(context::current(), port).await??;
         println!("Registering {i}k.bin on a peer");
-        c.register(context::current(), format!("{i}k.bin")).await?;
+        let record = FileRecord {
+            filename: format!("{i}k.bin"),
+            size: i * 1024,
+            digest: String::new(),
+            version: 0,
+            origin: keypair.id(),
+            modified: SystemTime::now(),
+            readers: None,
+        };
+        c.register(context::current(), record).await??;
     }
 
     // For each round, run a request on each client
     println!("Starting runs!");
     let mutex = Arc::new(RwLock::new(Vec::new()));
     for i in 0..args.num_requests {
-        future::join_all(clients.iter().map(|c| async {
+        future::join_all(clients.iter().map(|(c, _)| async {
             let d = Arc::clone(&mutex);
             let now = Instant::now();
             c.query(
                 context::current(),
                 Uuid::new_v4(),
-                format!("{}k.bin", i % 10 + 1),
+                Query::Exact(format!("{}k.bin", i % 10 + 1)),
                 args.q_ttl,
             )
             .await
@@ -168,7 +163,7 @@ async fn main() -> Result<()> {
     if args.plot {
         let mut plot = Plot::new();
         let x_axis = (1..=args.num_requests)
-            .flat_map(|x| repeat(x).take(args.concurrent))
+            .flat_map(|x| repeat_n(x, args.concurrent))
             .collect();
         let y_axis: Vec<_> = durations.iter().map(|d| d.as_micros()).collect();
         let trace = Scatter::new(x_axis, y_axis.clone())
@@ -218,5 +213,10 @@ async fn main() -> Result<()> {
         histogram.show();
     }
 
+    for node in nodes {
+        node.shutdown();
+        node.await;
+    }
+
     Ok(())
 }
@@ -0,0 +1,166 @@
+//! Thin client for a `nekopeer` daemon, sending a single request over its
+//! [tarpc::serde_transport::unix] control socket with a [ControlClient]
+//!
+//! Exits with the [Status] of the request.
+use std::{
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+
+use anyhow::Result;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use tarpc::{client, context, serde_transport::unix, tokio_serde::formats::Bincode};
+use tokio::fs;
+
+use nekop2p::{format_hits, ControlClient, Query, Status, Verification, CONTROL_SOCKET};
+
+#[derive(Deserialize)]
+struct Config {
+    /// Unix socket the daemon accepts control connections on (default [CONTROL_SOCKET])
+    control: Option<PathBuf>,
+}
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct Args {
+    /// Config file of the daemon to control
+    config: String,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+/// Requests to send to the daemon
+#[derive(Subcommand)]
+enum Command {
+    /// Register a file inside the daemon's share directories
+    Register { filename: String },
+
+    /// Download a file from the network into the daemon's share directories and seed it
+    Download {
+        filename: String,
+
+        /// Download from every peer holding the file at once
+        #[arg(long)]
+        swarm: bool,
+    },
+
+    /// Search the network for files matching a name or pattern
+    Search {
+        pattern: Query,
+
+        /// Only search the daemon's indexer instead of the whole network
+        #[arg(long)]
+        local: bool,
+
+        /// Only list holders that proved to their indexer that they have the file
+        #[arg(long)]
+        verified: bool,
+    },
+
+    /// Show what the daemon is connected to and sharing
+    Status,
+
+    /// Deregister the daemon's files and stop it
+    Shutdown,
+}
+
+/// Sends the [Command] to the daemon configured in [Args::config] and exits with its [Status]
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(x) => x.into(),
+        Err(e) => {
+            println!("{e}");
+            Status::Failed.into()
+        }
+    }
+}
+
+/// Sends the [Command] in `args` to the daemon
+async fn run(args: Args) -> Result<Status> {
+    let config: Config = toml::from_str(
+        &fs::read_to_string(args.config)
+            .await
+            .expect("missing config file"),
+    )
+    .expect("failed to parse config file");
+
+    let socket = config
+        .control
+        .unwrap_or_else(|| PathBuf::from(CONTROL_SOCKET));
+    let transport = unix::connect(&socket, Bincode::default).await?;
+    let client = ControlClient::new(client::Config::default(), transport).spawn();
+
+    // downloads and network-wide searches can easily outlast the default deadline
+    let mut ctx = context::current();
+    ctx.deadline = SystemTime::now() + Duration::from_secs(60 * 60);
+
+    let status = match args.command {
+        Command::Register { filename } => {
+            let status = client.register(ctx, filename.clone()).await?;
+            match status {
+                Status::Done => println!("Registered {filename} on index"),
+                Status::NotFound => println!("{filename} is not a file in the share root"),
+                Status::Failed => println!("Failed to register {filename}"),
+            }
+            status
+        }
+        Command::Download { filename, swarm } => {
+            let status = client.download(ctx, filename.clone(), swarm).await?;
+            match status {
+                Status::Done => println!("Downloaded {filename}"),
+                Status::NotFound => println!("No peers to download {filename} from"),
+                Status::Failed => println!("Failed to download {filename}"),
+            }
+            status
+        }
+        Command::Search {
+            pattern,
+            local,
+            verified,
+        } => {
+            let mut hits = client.search(ctx, pattern.clone(), local).await?;
+            if let (Some(x), true) = (&mut hits, verified) {
+                x.retain(|x| x.verification == Verification::Verified);
+            }
+            match hits {
+                Some(x) if x.is_empty() => {
+                    println!("No files matching {pattern}");
+                    Status::NotFound
+                }
+                Some(x) => {
+                    print!("{0}", format_hits(&x));
+                    Status::Done
+                }
+                None => {
+                    println!("Failed to retrieve peers for {pattern}");
+                    Status::Failed
+                }
+            }
+        }
+        Command::Status => {
+            let status = client.status(ctx).await?;
+            println!("Node {0}", status.id);
+            println!("Connected to indexer on {0}", status.indexer);
+            println!("Accepting inbound connections on {0}", status.dl_addr);
+            println!("Up for {0}", humantime::format_duration(status.uptime));
+            for dir in status.share_dirs {
+                println!("Sharing {0}", dir.display());
+            }
+            for filename in status.files {
+                println!("  {filename}");
+            }
+            Status::Done
+        }
+        Command::Shutdown => {
+            client.shutdown(ctx).await?;
+            println!("Daemon on {0} shut down", socket.display());
+            Status::Done
+        }
+    };
+
+    Ok(status)
+}
@@ -1,35 +1,59 @@
-//! Simple binary wrapping the reference implementation of [IndexerServer] in a
-//! [tarpc::serde_transport::tcp::connect].
+//! Simple binary wrapping the reference implementation of [nekop2p::IndexerServer] in an
+//! [IndexerNode].
 //!
-//! Utilizes two [DashMap]s as the underlying data structure for the [IndexerServer::index] and
-//! [IndexerServer::dl_ports].
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+//! Registrations are kept in memory, or on disk when `state` is configured. Holders are challenged
+//! to prove they have the files they register when `verify` or `audit_interval` is configured.
+use std::{net::SocketAddr, path::PathBuf, sync::Arc, time::Duration};
 
 use anyhow::Result;
 use clap::Parser;
-use dashmap::DashMap;
-use delay_map::HashSetDelay;
-use futures::{future, prelude::*};
 use serde::Deserialize;
-use tarpc::{
-    serde_transport::tcp,
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Bincode,
-};
-use tokio::{fs, sync::RwLock};
+use tokio::fs;
 
-use nekop2p::{Indexer, IndexerServer};
+use nekop2p::{IndexerNode, Keypair, NetworkKey, Security, Storage, PROTOCOL_VERSION};
 
 #[derive(Deserialize)]
 struct Config {
     /// Host to run on
     bind: SocketAddr,
 
-    /// Neighbors of [IndexerServer]
+    /// Neighbors of [nekop2p::IndexerServer]
     neighbors: Option<Vec<SocketAddr>>,
 
     /// Query Backtrace TTL (default 10 seconds)
     ttl: Option<u64>,
+
+    /// Snapshot file to persist the index in, with its log next to it (default not persisted)
+    state: Option<PathBuf>,
+
+    /// Seconds restored registrations wait for their peer to reconnect (default 60 seconds)
+    grace: Option<u64>,
+
+    /// Seconds a peer's registrations last without a heartbeat (default 30 seconds)
+    lease: Option<u64>,
+
+    /// Number of peers served at once (default 10)
+    max_connections: Option<usize>,
+
+    /// Number of connections served at once from the same IP address (default unlimited)
+    max_connections_per_peer: Option<u32>,
+
+    /// File the [Keypair] behind the indexer's ID is kept in, generated if missing (default a new
+    /// ID every start)
+    key: Option<PathBuf>,
+
+    /// Encrypt and authenticate every connection with Noise (default false)
+    encrypt: Option<bool>,
+
+    /// Secret shared by the nodes of a private network, which the indexer only serves and
+    /// forwards to (default any node)
+    network_secret: Option<String>,
+
+    /// Challenge every registration to prove its holder has the file (default false)
+    verify: Option<bool>,
+
+    /// Seconds between re-challenging a random sample of registrations (default never)
+    audit_interval: Option<u64>,
 }
 
 #[derive(Parser)]
@@ -38,7 +62,7 @@ struct Args {
     config: String,
 }
 
-/// Starts an [IndexerServer] on [Args::host] with [Args::port]
+/// Starts an [IndexerNode] on [Config::bind] and serves until it stops
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
@@ -51,36 +75,56 @@ async fn main() -> Result<()> {
 
     println!("Starting indexer on {0}", config.bind);
 
-    let index = Arc::new(DashMap::new());
-    let dl_ports = Arc::new(DashMap::new());
-    let neighbors = Arc::new(config.neighbors.unwrap_or_default());
-    let backtrace = Arc::new(RwLock::new(HashSetDelay::new(Duration::from_secs(
-        config.ttl.unwrap_or(10),
-    ))));
-    let listener = tcp::listen(config.bind, Bincode::default).await?;
-    listener
-        // Ignore accept errors.
-        .filter_map(|r| future::ready(r.ok()))
-        // Establish serve channel
-        .map(BaseChannel::with_defaults)
-        .map(|channel| {
-            let server = IndexerServer::new(
-                channel.transport().peer_addr().unwrap(),
-                &index,
-                &dl_ports,
-                &neighbors,
-                &backtrace,
-            );
-            channel
-                .execute(server.serve())
-                .for_each(|response| async move {
-                    tokio::spawn(response);
-                })
-        })
-        // Max 10 channels.
-        .buffer_unordered(10)
-        .for_each(|_| async {})
-        .await;
+    let storage = match config.state {
+        Some(path) => {
+            println!("Restoring index from {0}", path.display());
+            Storage::Disk {
+                path,
+                grace: Duration::from_secs(config.grace.unwrap_or(60)),
+            }
+        }
+        None => Storage::Memory,
+    };
+
+    let mut builder = IndexerNode::builder()
+        .bind(config.bind)
+        .neighbors(config.neighbors.unwrap_or_default())
+        .backtrace_ttl(Duration::from_secs(config.ttl.unwrap_or(10)))
+        .lease(Duration::from_secs(config.lease.unwrap_or(30)))
+        .storage(storage);
+    if let Some(n) = config.max_connections {
+        builder = builder.max_connections(n);
+    }
+    if let Some(n) = config.max_connections_per_peer {
+        builder = builder.max_connections_per_peer(n);
+    }
+    if let Some(path) = config.key {
+        builder = builder.keypair(&Arc::new(Keypair::load_or_generate(&path)?));
+    }
+
+    if config.encrypt == Some(true) {
+        println!("Encrypting connections with Noise");
+        builder = builder.security(Security::Noise);
+    }
+    if let Some(secret) = &config.network_secret {
+        println!("Only admitting members of the private network");
+        builder = builder.network(NetworkKey::from_secret(secret));
+    }
+
+    if config.verify == Some(true) {
+        println!("Challenging holders to prove they have the files they register");
+        builder = builder.verify(true);
+    }
+    if let Some(secs) = config.audit_interval {
+        builder = builder.audit_interval(Duration::from_secs(secs));
+    }
+
+    let node = builder.start().await?;
+    println!(
+        "Node ID {0} (protocol version {PROTOCOL_VERSION})",
+        node.id()
+    );
+    node.await;
 
     Ok(())
 }
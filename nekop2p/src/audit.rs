@@ -0,0 +1,205 @@
+//! Proof-of-possession challenges of the files registered with an [crate::IndexerServer]
+use std::{fmt, net::SocketAddr, slice, time::Duration};
+
+use dashmap::DashMap;
+use rand::{seq::SliceRandom, Rng};
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+
+use crate::{
+    download::{connect_peer, fetch_piece, offer},
+    piece_count, Endpoint, Features, FileRecord, Hit, IndexStore, NekoError, NodeError, PeerClient,
+    PeerId, Query, CHUNK_SIZE,
+};
+
+/// How long a holder has to connect, and then to answer the challenge of each file
+const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whether the holder of a registration proved that it has the file
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub enum Verification {
+    /// The holder hasn't been challenged yet, or couldn't be reached or read from
+    #[default]
+    Unverified,
+
+    /// The holder served a random piece of the file matching the Merkle root signed by its
+    /// origin
+    Verified,
+
+    /// The holder couldn't serve the file it registered
+    Failed,
+}
+
+impl fmt::Display for Verification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Verification::Unverified => write!(f, "unverified"),
+            Verification::Verified => write!(f, "verified"),
+            Verification::Failed => write!(f, "failed"),
+        }
+    }
+}
+
+/// Outcomes of the challenges an [crate::IndexerServer] sent to the holders of its registrations
+///
+/// A holder is challenged by connecting to the address it downloads from, as the peer the index
+/// lists, and reading a random piece of the file along with its proof. The piece has to match the
+/// Merkle root in metadata signed by the file's origin, and the metadata has to describe the same
+/// version the holder registered.
+pub struct Audits {
+    /// Endpoint challenges connect through
+    endpoint: Endpoint,
+
+    /// Whether every registration is challenged as it comes in
+    on_register: bool,
+
+    /// Outcome of the last challenge of each holder and filename, with the digest it was for
+    outcomes: DashMap<(PeerId, String), (String, Verification)>,
+}
+
+/// Whether `e` leaves open if the holder has the file, because it couldn't be reached or doesn't
+/// let the indexer read it
+fn inconclusive(e: &NodeError) -> bool {
+    matches!(
+        e,
+        NodeError::Rpc(_)
+            | NodeError::Io(_)
+            | NodeError::NoHello(_)
+            | NodeError::Refused(NekoError::Incompatible(..) | NekoError::PermissionDenied(_))
+    )
+}
+
+/// Have `peer` serve a random piece of the file described by `record` and check it against the
+/// Merkle root signed by the file's origin
+async fn prove(
+    peer: &PeerClient,
+    features: Features,
+    record: &FileRecord,
+) -> Result<(), NodeError> {
+    let filename = &record.filename;
+    let (metadata, size) = offer(peer, filename).await?;
+    let registered = metadata.digest == record.digest
+        && metadata.version == record.version
+        && metadata.origin == record.origin
+        && metadata.size == record.size;
+    if !registered || size != record.size {
+        return Err(NodeError::Transfer(format!(
+            "peer offers another version of {filename} than it registered"
+        )));
+    }
+
+    let piece = rand::thread_rng().gen_range(0..piece_count(size));
+    let start = piece * u64::from(CHUNK_SIZE);
+    let end = size.min(start + u64::from(CHUNK_SIZE));
+    fetch_piece(peer, features, filename, &metadata, start, end).await?;
+    Ok(())
+}
+
+impl Audits {
+    /// Create an empty set of outcomes for challenges sent through `endpoint`, challenging every
+    /// registration as it comes in if `on_register` is set
+    pub fn new(endpoint: &Endpoint, on_register: bool) -> Self {
+        Audits {
+            endpoint: endpoint.clone(),
+            on_register,
+            outcomes: DashMap::new(),
+        }
+    }
+
+    /// Whether every registration is challenged as it comes in
+    pub fn on_register(&self) -> bool {
+        self.on_register
+    }
+
+    /// How the registration of `record` by `holder` fared in its last challenge
+    pub fn status(&self, holder: PeerId, record: &FileRecord) -> Verification {
+        self.outcomes
+            .get(&(holder, record.filename.clone()))
+            .filter(|x| x.0 == record.digest)
+            .map_or(Verification::Unverified, |x| x.1)
+    }
+
+    /// Fill in how each of `hits` fared in its last challenge
+    pub fn annotate(&self, hits: &mut [Hit]) {
+        for hit in hits.iter_mut() {
+            hit.verification = self.status(hit.holder, &hit.record);
+        }
+    }
+
+    /// Forget the outcome of the challenge of `filename` from `holder`
+    pub fn forget(&self, holder: PeerId, filename: &str) {
+        self.outcomes.remove(&(holder, filename.to_owned()));
+    }
+
+    /// Forget the outcomes of every challenge of `holder`
+    pub fn forget_peer(&self, holder: PeerId) {
+        self.outcomes.retain(|(x, _), _| *x != holder);
+    }
+
+    /// Challenge `holder` downloading from `addr` to prove it has each file described in
+    /// `records`, over a single connection
+    pub async fn challenge(&self, holder: PeerId, addr: SocketAddr, records: &[FileRecord]) {
+        let connected = timeout(
+            CHALLENGE_TIMEOUT,
+            connect_peer(&self.endpoint, holder, &addr),
+        )
+        .await;
+        let (client, features) = match connected {
+            Ok(Ok(x)) => x,
+            Ok(Err(e)) if inconclusive(&e) => {
+                println!("Could not challenge {holder} at {addr}: {e}");
+                return;
+            }
+            Ok(Err(e)) => {
+                println!("{holder} failed challenges at {addr}: {e}");
+                for record in records {
+                    self.record(holder, record, Verification::Failed);
+                }
+                return;
+            }
+            Err(_) => {
+                println!("Could not challenge {holder} at {addr}: timed out");
+                return;
+            }
+        };
+
+        for record in records {
+            let filename = &record.filename;
+            match timeout(CHALLENGE_TIMEOUT, prove(&client, features, record)).await {
+                Ok(Ok(_)) => {
+                    println!("{holder} proved it has {filename}");
+                    self.record(holder, record, Verification::Verified);
+                }
+                Ok(Err(e)) if inconclusive(&e) => {
+                    println!("Could not challenge {holder} for {filename}: {e}");
+                }
+                Ok(Err(e)) => {
+                    println!("{holder} failed challenge for {filename}: {e}");
+                    self.record(holder, record, Verification::Failed);
+                }
+                Err(_) => println!("Could not challenge {holder} for {filename}: timed out"),
+            }
+        }
+    }
+
+    /// Challenge the holders of up to `n` registrations in `store`, picked at random
+    pub async fn sample(&self, store: &dyn IndexStore, n: usize) {
+        let hits: Vec<_> = store
+            .search(&Query::Prefix(String::new()))
+            .choose_multiple(&mut rand::thread_rng(), n)
+            .cloned()
+            .collect();
+        for hit in hits {
+            self.challenge(hit.holder, hit.peer, slice::from_ref(&hit.record))
+                .await;
+        }
+    }
+
+    /// Remember how the registration of `record` by `holder` fared
+    fn record(&self, holder: PeerId, record: &FileRecord, outcome: Verification) {
+        self.outcomes.insert(
+            (holder, record.filename.clone()),
+            (record.digest.clone(), outcome),
+        );
+    }
+}
@@ -0,0 +1,53 @@
+use std::{net::SocketAddr, path::PathBuf, process::ExitCode, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::PeerId;
+
+/// Socket a peer daemon accepts [crate::Control] connections on unless configured otherwise
+pub const CONTROL_SOCKET: &str = "nekopeer.sock";
+
+/// Outcome of a peer command, also used as the exit status of the binaries running it
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Status {
+    /// The command succeeded (exit status 0)
+    Done,
+
+    /// The file or pattern given matched nothing (exit status 1)
+    NotFound,
+
+    /// The command failed (exit status 2)
+    Failed,
+}
+
+impl From<Status> for ExitCode {
+    fn from(status: Status) -> Self {
+        match status {
+            Status::Done => ExitCode::SUCCESS,
+            Status::NotFound => ExitCode::from(1),
+            Status::Failed => ExitCode::from(2),
+        }
+    }
+}
+
+/// State of a peer daemon as reported by [crate::Control::status]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct NodeStatus {
+    /// ID the daemon introduces itself with
+    pub id: PeerId,
+
+    /// Indexer the daemon is connected to
+    pub indexer: SocketAddr,
+
+    /// Address the daemon's [crate::PeerServer] accepts downloads on
+    pub dl_addr: SocketAddr,
+
+    /// Directories the daemon shares files from and downloads into
+    pub share_dirs: Vec<PathBuf>,
+
+    /// Files currently inside the share directories
+    pub files: Vec<String>,
+
+    /// How long the daemon has been running
+    pub uptime: Duration,
+}
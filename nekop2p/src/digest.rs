@@ -0,0 +1,264 @@
+use std::{io, path::Path};
+
+use sha2::{Digest, Sha256};
+use tokio::{fs, io::AsyncReadExt};
+
+use crate::{Metadata, CHUNK_SIZE};
+
+/// SHA-256 hash of a piece or of a node in a [MerkleTree]
+pub type Hash = [u8; 32];
+
+/// Digests of a file's contents
+pub struct FileDigest {
+    /// Size of the file in bytes
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 digest of the whole file
+    pub digest: String,
+
+    /// Merkle tree over the file's [CHUNK_SIZE] pieces
+    pub tree: MerkleTree,
+}
+
+/// Merkle tree over the fixed-size pieces of a file
+///
+/// Leaves are `SHA-256(0x00 || piece)` and inner nodes are `SHA-256(0x01 || left || right)`. A
+/// node without a sibling is promoted to the next level unchanged. An empty file has a single
+/// empty piece.
+pub struct MerkleTree {
+    /// Every level of the tree, starting from the leaves and ending with the root
+    levels: Vec<Vec<Hash>>,
+}
+
+/// Hash a single piece into a leaf
+fn leaf_hash(piece: &[u8]) -> Hash {
+    Sha256::new()
+        .chain_update([0])
+        .chain_update(piece)
+        .finalize()
+        .into()
+}
+
+/// Hash two children into their parent
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    Sha256::new()
+        .chain_update([1])
+        .chain_update(left)
+        .chain_update(right)
+        .finalize()
+        .into()
+}
+
+/// Number of pieces a file of `size` bytes is split into
+pub fn piece_count(size: u64) -> u64 {
+    size.div_ceil(CHUNK_SIZE.into()).max(1)
+}
+
+impl MerkleTree {
+    /// Build a tree from its `leaves`
+    fn from_leaves(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels.last().unwrap().len() > 1 {
+            let next = levels
+                .last()
+                .unwrap()
+                .chunks(2)
+                .map(|pair| match pair {
+                    [left, right] => node_hash(left, right),
+                    [single] => *single,
+                    _ => unreachable!(),
+                })
+                .collect();
+            levels.push(next);
+        }
+        MerkleTree { levels }
+    }
+
+    /// Root of the tree
+    pub fn root(&self) -> Hash {
+        self.levels.last().unwrap()[0]
+    }
+
+    /// Hex-encoded root of the tree, as stored in [Metadata::root]
+    pub fn root_hex(&self) -> String {
+        hex::encode(self.root())
+    }
+
+    /// Sibling hashes needed to check `piece` against [MerkleTree::root], from the leaves up
+    pub fn proof(&self, piece: u64) -> Option<Vec<Hash>> {
+        let mut index = usize::try_from(piece).ok()?;
+        if index >= self.levels[0].len() {
+            return None;
+        }
+
+        let mut proof = Vec::new();
+        for level in self.levels.iter().take(self.levels.len() - 1) {
+            if let Some(sibling) = level.get(index ^ 1) {
+                proof.push(*sibling);
+            }
+            index /= 2;
+        }
+        Some(proof)
+    }
+}
+
+/// Check that `data` is piece number `piece` of the file described by `metadata`, using the
+/// sibling hashes in `proof`
+pub fn verify_piece(metadata: &Metadata, piece: u64, data: &[u8], proof: &[Hash]) -> bool {
+    let mut root = Hash::default();
+    if hex::decode_to_slice(&metadata.root, &mut root).is_err() {
+        return false;
+    }
+
+    let mut width = piece_count(metadata.size);
+    if piece >= width {
+        return false;
+    }
+
+    let mut index = piece;
+    let mut hash = leaf_hash(data);
+    let mut proof = proof.iter();
+    while width > 1 {
+        if index ^ 1 < width {
+            let sibling = match proof.next() {
+                Some(x) => x,
+                None => return false,
+            };
+            hash = if index & 1 == 0 {
+                node_hash(&hash, sibling)
+            } else {
+                node_hash(sibling, &hash)
+            };
+        }
+        index /= 2;
+        width = width.div_ceil(2);
+    }
+
+    proof.next().is_none() && hash == root
+}
+
+/// Hash the contents of the file at `path`, returning its size, SHA-256 digest and Merkle tree
+pub async fn hash_file(path: impl AsRef<Path>) -> io::Result<FileDigest> {
+    let mut file = fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut leaves = Vec::new();
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    let mut size = 0;
+    loop {
+        // fill a whole piece (or whatever is left of the file)
+        let mut filled = 0;
+        while filled < buf.len() {
+            let n = file.read(&mut buf[filled..]).await?;
+            if n == 0 {
+                break;
+            }
+            filled += n;
+        }
+
+        if filled > 0 || leaves.is_empty() {
+            hasher.update(&buf[..filled]);
+            leaves.push(leaf_hash(&buf[..filled]));
+            size += filled as u64;
+        }
+        if filled < buf.len() {
+            break;
+        }
+    }
+
+    Ok(FileDigest {
+        size,
+        digest: hex::encode(hasher.finalize()),
+        tree: MerkleTree::from_leaves(leaves),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Keypair;
+
+    /// Tree over `n` small pieces, each filled with its index, along with the pieces
+    fn tree_of(n: usize) -> (MerkleTree, Vec<Vec<u8>>) {
+        let pieces: Vec<_> = (0..n).map(|i| vec![i as u8; 3]).collect();
+        let tree = MerkleTree::from_leaves(pieces.iter().map(|x| leaf_hash(x)).collect());
+        (tree, pieces)
+    }
+
+    /// Metadata for a file of `n` whole pieces hashing to `tree`
+    fn metadata_of(tree: MerkleTree, n: usize) -> Metadata {
+        let hashed = FileDigest {
+            size: n as u64 * u64::from(CHUNK_SIZE),
+            digest: String::new(),
+            tree,
+        };
+        Metadata::new(&Keypair::generate(), "file", 0, 1, &hashed, None)
+    }
+
+    #[test]
+    fn every_piece_verifies() {
+        for n in 1..=9 {
+            let (tree, pieces) = tree_of(n);
+            let proofs: Vec<_> = (0..n as u64).map(|i| tree.proof(i).unwrap()).collect();
+            assert!(tree.proof(n as u64).is_none());
+
+            let metadata = metadata_of(tree, n);
+            for (i, (piece, proof)) in pieces.iter().zip(&proofs).enumerate() {
+                assert!(
+                    verify_piece(&metadata, i as u64, piece, proof),
+                    "{i} of {n}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn promoted_piece_verifies() {
+        // the last of 5 pieces is promoted twice before meeting the rest of the tree
+        let (tree, pieces) = tree_of(5);
+        let proof = tree.proof(4).unwrap();
+        assert_eq!(proof.len(), 1);
+        assert!(verify_piece(&metadata_of(tree, 5), 4, &pieces[4], &proof));
+    }
+
+    #[test]
+    fn tampering_fails() {
+        let (tree, pieces) = tree_of(6);
+        let proof = tree.proof(2).unwrap();
+        let metadata = metadata_of(tree, 6);
+
+        assert!(!verify_piece(&metadata, 2, &pieces[3], &proof));
+        assert!(!verify_piece(&metadata, 3, &pieces[2], &proof));
+        assert!(!verify_piece(&metadata, 6, &pieces[2], &proof));
+        assert!(!verify_piece(
+            &metadata,
+            2,
+            &pieces[2],
+            &proof[..proof.len() - 1]
+        ));
+        let mut longer = proof.clone();
+        longer.push(Hash::default());
+        assert!(!verify_piece(&metadata, 2, &pieces[2], &longer));
+    }
+
+    #[tokio::test]
+    async fn hashed_file_pieces_verify() {
+        let path = std::env::temp_dir().join(format!("nekop2p-digest-{0}", std::process::id()));
+        for size in [0, 1, 2 * CHUNK_SIZE as usize + CHUNK_SIZE as usize / 2] {
+            let data: Vec<_> = (0..size).map(|i| (i % 251) as u8).collect();
+            fs::write(&path, &data).await.unwrap();
+            let hashed = hash_file(&path).await.unwrap();
+            assert_eq!(hashed.size, size as u64);
+            assert_eq!(hashed.digest, hex::encode(Sha256::digest(&data)));
+
+            let metadata = Metadata::new(&Keypair::generate(), "file", 0, 1, &hashed, None);
+            let pieces = piece_count(size as u64);
+            for piece in 0..pieces {
+                let start = (piece * u64::from(CHUNK_SIZE)) as usize;
+                let end = size.min(start + CHUNK_SIZE as usize);
+                let proof = hashed.tree.proof(piece).unwrap();
+                assert!(verify_piece(&metadata, piece, &data[start..end], &proof));
+            }
+        }
+        fs::remove_file(&path).await.unwrap();
+    }
+}
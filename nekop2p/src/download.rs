@@ -0,0 +1,431 @@
+//! Verified, resumable downloads from one or more [crate::PeerServer]s
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures::prelude::*;
+use tarpc::{client, context};
+use tokio::{sync::Mutex as AsyncMutex, time::timeout};
+
+use crate::{
+    identity::identify_message, node::EventHook, partial::Partial, verify_piece, Endpoint,
+    Features, Hello, MerkleTree, Metadata, NekoError, NodeError, NodeEvent, PeerClient, PeerId,
+    Security, CHUNK_SIZE,
+};
+
+/// Number of times a peer may fail a piece before it is dropped from a swarm download
+const MAX_PEER_FAILURES: usize = 3;
+
+/// How long to wait on a single piece before handing it to another peer
+const PIECE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Connect through `endpoint` to the [crate::PeerServer] of `holder` listening on `addr` and
+/// introduce ourselves, proving both sides' IDs through [crate::Peer::identify] unless the Noise
+/// handshake already did, and return the client along with the features both sides support
+pub async fn connect_peer(
+    endpoint: &Endpoint,
+    holder: PeerId,
+    addr: &SocketAddr,
+) -> Result<(PeerClient, Features), NodeError> {
+    let transport = endpoint.connect(*addr, Some(holder)).await?;
+    let client = PeerClient::new(client::Config::default(), transport).spawn();
+    let hello = Hello::new(endpoint.id(), Features::ALL);
+    let theirs = client
+        .hello(context::current(), hello)
+        .await
+        .map_err(|_| NodeError::NoHello(addr.to_string()))??;
+    let features = hello.negotiate(&theirs)?;
+
+    if endpoint.security() == Security::Plaintext {
+        if theirs.node_id != holder {
+            return Err(NodeError::Transfer(format!(
+                "{addr} is {0}, not {holder}",
+                theirs.node_id
+            )));
+        }
+        let proof = endpoint
+            .keypair()
+            .sign(&identify_message(&theirs.node_id, &theirs.challenge));
+        let answer = client.identify(context::current(), proof).await??;
+        if !holder.verify(&identify_message(&hello.node_id, &hello.challenge), &answer) {
+            return Err(NekoError::BadSignature.into());
+        }
+    }
+    Ok((client, features))
+}
+
+/// Undo the compression of a chunk sent with [Features::COMPRESSION], refusing anything that
+/// would inflate past [CHUNK_SIZE]
+fn decompress(chunk: &[u8]) -> Result<Vec<u8>, NodeError> {
+    let bad = || NodeError::Transfer("peer sent a malformed compressed chunk".to_owned());
+    let size: [u8; 4] = chunk.get(..4).ok_or_else(bad)?.try_into().unwrap();
+    if u32::from_le_bytes(size) > CHUNK_SIZE {
+        return Err(bad());
+    }
+    lz4_flex::decompress_size_prepended(chunk).map_err(|_| bad())
+}
+
+/// Fetch the piece `[start, end)` of `filename` from `peer` and check it against the Merkle root
+/// in `metadata`
+pub async fn fetch_piece(
+    peer: &PeerClient,
+    features: Features,
+    filename: &str,
+    metadata: &Metadata,
+    start: u64,
+    end: u64,
+) -> Result<Vec<u8>, NodeError> {
+    let piece = start / u64::from(CHUNK_SIZE);
+    let (chunk, proof) = future::join(
+        peer.read_chunk(
+            context::current(),
+            filename.to_owned(),
+            start,
+            (end - start) as u32,
+            features.contains(Features::COMPRESSION),
+        ),
+        peer.get_proof(context::current(), filename.to_owned(), piece),
+    )
+    .await;
+
+    let mut chunk = chunk??;
+    if features.contains(Features::COMPRESSION) {
+        chunk = decompress(&chunk)?;
+    }
+    let proof = proof??;
+    if !verify_piece(metadata, piece, &chunk, &proof) {
+        return Err(NodeError::BadPiece(piece));
+    }
+    Ok(chunk)
+}
+
+/// Metadata and size of `filename` as offered by `peer`
+pub async fn offer(peer: &PeerClient, filename: &str) -> Result<(Metadata, u64), NodeError> {
+    let metadata = peer
+        .get_metadata(context::current(), filename.to_owned())
+        .await??;
+    if !metadata.verify(filename) {
+        return Err(NodeError::Transfer(format!(
+            "metadata of {filename} is not signed by its origin {0}",
+            metadata.origin
+        )));
+    }
+    let size = peer
+        .file_size(context::current(), filename.to_owned())
+        .await??;
+    Ok((metadata, size))
+}
+
+/// Start or resume the partial download of `filename` to `path`, reporting how much of it was
+/// already received
+async fn resume(
+    path: &Path,
+    filename: &str,
+    metadata: &Metadata,
+    size: u64,
+    report: &EventHook,
+) -> Result<Partial, NodeError> {
+    let partial = Partial::resume(path, metadata, size).await?;
+    if partial.received() > 0 {
+        report(&NodeEvent::Resuming(
+            filename.to_owned(),
+            partial.received(),
+            size,
+        ));
+    }
+    Ok(partial)
+}
+
+/// Check the completed `partial` download of `filename` against the origin's digest and make
+/// sure `peer` still offers the same version, then move it into place at `path` and return its
+/// Merkle tree
+async fn complete(
+    partial: Partial,
+    peer: &PeerClient,
+    filename: &str,
+    path: &Path,
+    metadata: &Metadata,
+    suspects: &str,
+) -> Result<MerkleTree, NodeError> {
+    let Some(tree) = partial.verify().await? else {
+        Partial::discard(path).await;
+        return Err(NodeError::Transfer(format!(
+            "{filename} does not match the origin's digest, corrupt or malicious peer among \
+             {suspects}"
+        )));
+    };
+
+    // make sure the origin didn't publish a new version while we were downloading
+    let current = peer
+        .get_metadata(context::current(), filename.to_owned())
+        .await?;
+    if current.as_ref() != Ok(metadata) {
+        Partial::discard(path).await;
+        return Err(NodeError::Transfer(format!(
+            "{filename} changed during download, discarded partial data"
+        )));
+    }
+
+    partial.finish().await?;
+    Ok(tree)
+}
+
+/// Fetch the missing pieces of `filename` from `peer` into the `.part` file of `path`, resuming
+/// any partial download of the same version, and move it into place once complete, returning its
+/// [Metadata] and Merkle tree
+async fn fetch_from(
+    addr: &SocketAddr,
+    peer: &PeerClient,
+    features: Features,
+    filename: &str,
+    path: &Path,
+    report: &EventHook,
+) -> Result<(Metadata, MerkleTree), NodeError> {
+    let (metadata, size) = offer(peer, filename).await?;
+    if size != metadata.size {
+        return Err(NodeError::Transfer(format!(
+            "peer offers {size} bytes of {filename} but its metadata says {0}",
+            metadata.size
+        )));
+    }
+
+    let mut partial = resume(path, filename, &metadata, size, report).await?;
+    for (start, end) in partial.pieces() {
+        let chunk = fetch_piece(peer, features, filename, &metadata, start, end).await?;
+        partial.write(start, &chunk).await?;
+    }
+
+    let tree = complete(partial, peer, filename, path, &metadata, &addr.to_string()).await?;
+    Ok((metadata, tree))
+}
+
+/// Download `filename` to `path` through `endpoint` from each of `peers` (holders along with
+/// their addresses) in turn until one completes it, asking every peer only for the pieces that
+/// are still missing, and return its [Metadata] and Merkle tree
+///
+/// Peers that fail are passed on to `report` before moving on to the next one.
+pub async fn download_resumable(
+    peers: &[(PeerId, SocketAddr)],
+    endpoint: &Endpoint,
+    filename: &str,
+    path: &Path,
+    report: &EventHook,
+) -> Result<(Metadata, MerkleTree), NodeError> {
+    for (holder, peer) in peers {
+        let (client, features) = match connect_peer(endpoint, *holder, peer).await {
+            Ok(x) => x,
+            Err(e) => {
+                report(&NodeEvent::PeerFailed(filename.to_owned(), *peer, e));
+                continue;
+            }
+        };
+
+        report(&NodeEvent::Downloading(filename.to_owned(), *peer));
+        match fetch_from(peer, &client, features, filename, path, report).await {
+            Ok(x) => return Ok(x),
+            Err(e) => report(&NodeEvent::PeerFailed(filename.to_owned(), *peer, e)),
+        }
+    }
+
+    Err(NodeError::Transfer(format!(
+        "no peer could complete {filename}, partial data kept for resume"
+    )))
+}
+
+/// Pieces shared between the workers of a swarm download
+struct Pieces {
+    /// Pieces nobody is working on yet
+    pending: VecDeque<(u64, u64)>,
+
+    /// Number of pieces currently being fetched
+    in_flight: usize,
+
+    /// Peers that failed each piece, by the offset it starts at
+    failed: HashMap<u64, HashSet<SocketAddr>>,
+
+    /// Peers still fetching pieces
+    active: HashSet<SocketAddr>,
+}
+
+impl Pieces {
+    /// Take the next pending piece for `peer`, skipping pieces it already failed unless every
+    /// peer still fetching has failed them too
+    fn next(&mut self, peer: SocketAddr) -> Option<(u64, u64)> {
+        let i = self.pending.iter().position(|(start, _)| {
+            self.failed
+                .get(start)
+                .is_none_or(|x| !x.contains(&peer) || self.active.is_subset(x))
+        })?;
+        self.in_flight += 1;
+        self.pending.remove(i)
+    }
+}
+
+/// Pull pieces of `filename` off `pieces` and fetch them from `peer` (along with the features
+/// both sides support) until none are left, or until the peer has failed [MAX_PEER_FAILURES]
+/// times, and return how many pieces it served. Failed or slow pieces are put at the back for the
+/// other workers to pick up, and the peer only retries them once no other peer is left.
+async fn swarm_worker(
+    addr: SocketAddr,
+    (peer, features): &(PeerClient, Features),
+    filename: &str,
+    metadata: &Metadata,
+    pieces: &Mutex<Pieces>,
+    partial: &AsyncMutex<Partial>,
+    report: &EventHook,
+) -> Result<usize, NodeError> {
+    let mut failures = 0;
+    let mut served = 0;
+    loop {
+        let piece = {
+            let mut pieces = pieces.lock().unwrap();
+            match pieces.next(addr) {
+                Some(x) => Some(x),
+                None if pieces.pending.is_empty() && pieces.in_flight == 0 => break,
+                None => None,
+            }
+        };
+
+        // everything is in flight or left to other peers, wait in case a piece gets handed back
+        let Some((start, end)) = piece else {
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            continue;
+        };
+
+        let fetch = fetch_piece(peer, *features, filename, metadata, start, end);
+        let result = match timeout(PIECE_TIMEOUT, fetch).await {
+            Ok(Ok(chunk)) => match partial.lock().await.write(start, &chunk).await {
+                Ok(_) => Ok(()),
+                Err(e) => {
+                    let mut pieces = pieces.lock().unwrap();
+                    pieces.in_flight -= 1;
+                    pieces.active.remove(&addr);
+                    return Err(e.into());
+                }
+            },
+            Ok(Err(e)) => Err(e),
+            Err(_) => Err(NodeError::Transfer("timed out".to_owned())),
+        };
+
+        let mut pieces = pieces.lock().unwrap();
+        pieces.in_flight -= 1;
+        match result {
+            Ok(_) => served += 1,
+            Err(e) => {
+                pieces.pending.push_back((start, end));
+                pieces.failed.entry(start).or_default().insert(addr);
+                failures += 1;
+
+                // a peer sending bad data can't be trusted with any other piece either
+                let dropped = matches!(e, NodeError::BadPiece(_)) || failures >= MAX_PEER_FAILURES;
+                report(&NodeEvent::PeerFailed(filename.to_owned(), addr, e));
+                if dropped {
+                    break;
+                }
+            }
+        }
+    }
+    pieces.lock().unwrap().active.remove(&addr);
+    Ok(served)
+}
+
+/// Download `filename` to `path` through `endpoint` from all `peers` (holders along with their
+/// addresses) at once, splitting it into pieces that are spread across every peer offering the
+/// newest version, and return its [Metadata] and Merkle tree
+///
+/// Peers that are skipped or fail pieces are passed on to `report`, along with how many pieces
+/// each peer served.
+pub async fn download_swarm(
+    peers: &[(PeerId, SocketAddr)],
+    endpoint: &Endpoint,
+    filename: &str,
+    path: &Path,
+    report: &EventHook,
+) -> Result<(Metadata, MerkleTree), NodeError> {
+    // find out what each peer is offering
+    let offers = future::join_all(peers.iter().map(|(holder, addr)| async move {
+        let (client, features) = connect_peer(endpoint, *holder, addr).await?;
+        let (metadata, size) = offer(&client, filename).await?;
+        Ok::<_, NodeError>((*addr, (client, features), metadata, size))
+    }))
+    .await;
+    let offers: Vec<_> = offers
+        .into_iter()
+        .zip(peers)
+        .filter_map(|(o, (_, addr))| match o {
+            Ok(x) => Some(x),
+            Err(e) => {
+                report(&NodeEvent::PeerFailed(filename.to_owned(), *addr, e));
+                None
+            }
+        })
+        .collect();
+
+    // only swarm from peers that agree on the newest version
+    let (metadata, size) = offers
+        .iter()
+        .max_by_key(|(_, _, m, _)| m.version)
+        .map(|(_, _, m, s)| (m.clone(), *s))
+        .ok_or_else(|| NodeError::Transfer(format!("no peers reachable for {filename}")))?;
+    let holders: Vec<_> = offers
+        .into_iter()
+        .filter(|(_, _, m, s)| *m == metadata && *s == size && *s == m.size)
+        .collect();
+    if holders.is_empty() {
+        return Err(NodeError::Transfer(format!(
+            "no peer offers {filename} at the size its metadata says"
+        )));
+    }
+    report(&NodeEvent::Swarming(filename.to_owned(), holders.len()));
+
+    let partial = resume(path, filename, &metadata, size, report).await?;
+    let pieces = Mutex::new(Pieces {
+        pending: partial.pieces().into(),
+        in_flight: 0,
+        failed: HashMap::new(),
+        active: holders.iter().map(|(addr, _, _, _)| *addr).collect(),
+    });
+    let partial = AsyncMutex::new(partial);
+    let served = future::join_all(holders.iter().map(|(addr, peer, _, _)| {
+        swarm_worker(*addr, peer, filename, &metadata, &pieces, &partial, report)
+    }))
+    .await;
+
+    let mut failed = None;
+    for ((addr, _, _, _), result) in holders.iter().zip(served) {
+        match result {
+            Ok(n) => report(&NodeEvent::Served(filename.to_owned(), *addr, n)),
+            Err(e) => failed = Some(e),
+        }
+    }
+
+    let partial = partial.into_inner();
+    if !partial.pieces().is_empty() {
+        // writing the partial data failing isn't the peers' fault
+        return Err(failed.unwrap_or_else(|| {
+            NodeError::Transfer(format!(
+                "ran out of peers for {filename}, partial data kept for resume"
+            ))
+        }));
+    }
+
+    let suspects: Vec<_> = holders
+        .iter()
+        .map(|(addr, _, _, _)| addr.to_string())
+        .collect();
+    let (client, _) = &holders[0].1;
+    let tree = complete(
+        partial,
+        client,
+        filename,
+        path,
+        &metadata,
+        &suspects.join(", "),
+    )
+    .await?;
+    Ok((metadata, tree))
+}
@@ -0,0 +1,86 @@
+use std::{error::Error, fmt, io};
+
+use serde::{Deserialize, Serialize};
+
+/// Reason an [crate::Indexer] or [crate::Peer] turned down a request
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum NekoError {
+    /// The other side speaks the first protocol version, which isn't compatible with the second
+    Incompatible(u16, u16),
+
+    /// The connection hasn't proven which peer is on the other end through
+    /// [crate::Indexer::identify]
+    Unidentified,
+
+    /// A signature doesn't match the key it claims to be made with
+    BadSignature,
+
+    /// The peer hasn't told the indexer its download port through [crate::Indexer::set_port]
+    UnknownPeer,
+
+    /// The peer's lease lapsed, so it has to set its port and register its files again
+    LeaseLapsed,
+
+    /// The port can't be downloaded from
+    InvalidPort(u16),
+
+    /// The filename can't be registered
+    InvalidName(String),
+
+    /// The file isn't registered by the peer
+    NotRegistered(String),
+
+    /// The file isn't shared by the peer
+    NotFound(String),
+
+    /// The file is shared but the peer isn't allowed to read it
+    PermissionDenied(String),
+
+    /// The file is shared without metadata describing its version
+    NoMetadata(String),
+
+    /// The piece is past the end of the file
+    NoSuchPiece(u64),
+
+    /// The invalidation wasn't applied, for the given reason
+    InvalidationRejected(String),
+
+    /// Reading the file failed for another reason
+    Io(String),
+}
+
+impl NekoError {
+    /// Describe `e`, raised while reading the shared `filename`
+    pub fn io(filename: &str, e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::NotFound => NekoError::NotFound(filename.to_owned()),
+            io::ErrorKind::PermissionDenied => NekoError::PermissionDenied(filename.to_owned()),
+            _ => NekoError::Io(format!("{filename}: {e}")),
+        }
+    }
+}
+
+impl fmt::Display for NekoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NekoError::Incompatible(x, y) => {
+                write!(f, "protocol version {x} is not compatible with version {y}")
+            }
+            NekoError::Unidentified => write!(f, "peer has not identified itself"),
+            NekoError::BadSignature => write!(f, "signature does not match the claimed peer ID"),
+            NekoError::UnknownPeer => write!(f, "peer has not set its download port on the index"),
+            NekoError::LeaseLapsed => write!(f, "lease on the index lapsed"),
+            NekoError::InvalidPort(x) => write!(f, "{x} is not a valid download port"),
+            NekoError::InvalidName(x) => write!(f, "{x:?} is not a valid filename"),
+            NekoError::NotRegistered(x) => write!(f, "{x} is not registered by this peer"),
+            NekoError::NotFound(x) => write!(f, "{x} is not shared by the peer"),
+            NekoError::PermissionDenied(x) => write!(f, "permission denied reading {x}"),
+            NekoError::NoMetadata(x) => write!(f, "no metadata for {x}"),
+            NekoError::NoSuchPiece(x) => write!(f, "piece {x} is past the end of the file"),
+            NekoError::InvalidationRejected(x) => write!(f, "invalidation rejected: {x}"),
+            NekoError::Io(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+impl Error for NekoError {}
@@ -0,0 +1,184 @@
+use std::{fmt, ops::BitAnd};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use tarpc::context;
+
+use crate::{
+    identity::identify_message, IndexerClient, Keypair, NekoError, NodeError, PeerId, Signature,
+};
+
+/// Revision of the RPC scheme spoken by this release, bumped whenever [crate::Indexer] or
+/// [crate::Peer] change in a way older releases can't decode
+pub const PROTOCOL_VERSION: u16 = 5;
+
+/// Set of optional protocol features a node supports
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Features(u32);
+
+impl Features {
+    /// No optional features
+    pub const NONE: Features = Features(0);
+
+    /// [crate::Peer::read_chunk] can send pieces compressed with LZ4
+    pub const COMPRESSION: Features = Features(1 << 0);
+
+    /// Every optional feature this release supports
+    pub const ALL: Features = Features::COMPRESSION;
+
+    /// Names of the features, in bit order
+    const NAMES: [(Features, &'static str); 1] = [(Features::COMPRESSION, "compression")];
+
+    /// Whether every feature in `other` is also in this set
+    pub fn contains(self, other: Features) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitAnd for Features {
+    type Output = Features;
+
+    fn bitand(self, rhs: Features) -> Features {
+        Features(self.0 & rhs.0)
+    }
+}
+
+impl fmt::Display for Features {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = Features::NAMES
+            .iter()
+            .filter(|(x, _)| self.contains(*x))
+            .map(|(_, name)| *name)
+            .collect();
+        if names.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{0}", names.join(", "))
+        }
+    }
+}
+
+/// What a node tells the other side of a connection about itself through
+/// [crate::Indexer::hello] or [crate::Peer::hello]
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct Hello {
+    /// [PROTOCOL_VERSION] of the node
+    pub version: u16,
+
+    /// ID the node claims, proven through [crate::Indexer::identify]
+    pub node_id: PeerId,
+
+    /// Optional features the node supports
+    pub features: Features,
+
+    /// Random bytes the other side signs to prove it holds the key behind its own `node_id`
+    pub challenge: [u8; 32],
+}
+
+impl Hello {
+    /// Introduce a node of this release with `node_id`, supporting `features`, along with a
+    /// fresh challenge
+    pub fn new(node_id: PeerId, features: Features) -> Self {
+        let mut challenge = [0; 32];
+        rand::thread_rng().fill_bytes(&mut challenge);
+        Hello {
+            version: PROTOCOL_VERSION,
+            node_id,
+            features,
+            challenge,
+        }
+    }
+
+    /// Check that `other` speaks the same protocol, returning the features both sides support
+    pub fn negotiate(&self, other: &Hello) -> Result<Features, NekoError> {
+        if other.version != self.version {
+            return Err(NekoError::Incompatible(other.version, self.version));
+        }
+        Ok(self.features & other.features)
+    }
+}
+
+/// What a server knows about the node on the other end of one of its connections
+#[derive(Default)]
+pub(crate) struct Session {
+    /// [Hello] the node sent, and the one it was answered with
+    hellos: Option<(Hello, Hello)>,
+
+    /// ID the node proved it holds the key of, through its `identify` RPC or the Noise
+    /// handshake
+    peer: Option<PeerId>,
+
+    /// ID the node proved in the Noise handshake, if the connection is encrypted
+    transport: Option<PeerId>,
+}
+
+impl Session {
+    /// Session of a connection whose node proved to be `transport` in the Noise handshake, if
+    /// it is encrypted
+    pub fn new(transport: Option<PeerId>) -> Self {
+        Session {
+            hellos: None,
+            peer: None,
+            transport,
+        }
+    }
+
+    /// ID the node proved it holds the key of
+    pub fn peer(&self) -> Option<PeerId> {
+        self.peer
+    }
+
+    /// Remember that the node greeted with `theirs` and was answered with `ours`, refusing it if
+    /// it claims another ID than its connection proved
+    pub fn greet(&mut self, theirs: Hello, ours: Hello) -> Result<(), NekoError> {
+        // a node on an encrypted connection already proved its ID, and may not claim another
+        if self.transport.is_some_and(|x| x != theirs.node_id) {
+            return Err(NekoError::BadSignature);
+        }
+
+        // a new hello starts over, so the node has to identify itself again unless it already
+        // did in the Noise handshake
+        self.hellos = Some((theirs, ours));
+        self.peer = self.transport;
+        Ok(())
+    }
+
+    /// Accept `signature` over the challenge the node was answered with as proof that it holds
+    /// the key behind the ID it claimed, returning both [Hello]s
+    pub fn identify(&mut self, signature: &Signature) -> Result<(Hello, Hello), NekoError> {
+        let (theirs, ours) = self.hellos.ok_or(NekoError::Unidentified)?;
+        let message = identify_message(&ours.node_id, &ours.challenge);
+        if !theirs.node_id.verify(&message, signature) {
+            return Err(NekoError::BadSignature);
+        }
+        self.peer = Some(theirs.node_id);
+        Ok((theirs, ours))
+    }
+}
+
+/// Exchange [Hello]s with the indexer behind `client` as the node holding `keypair`, supporting
+/// `features`, and prove both sides' identities through [crate::Indexer::identify]
+///
+/// Returns the features both sides support.
+pub async fn handshake(
+    client: &IndexerClient,
+    keypair: &Keypair,
+    features: Features,
+) -> Result<Features, NodeError> {
+    let ours = Hello::new(keypair.id(), features);
+    let theirs = client
+        .hello(context::current(), ours)
+        .await
+        .map_err(|_| NodeError::NoHello("indexer".to_owned()))??;
+    let features = ours.negotiate(&theirs)?;
+
+    let proof = keypair.sign(&identify_message(&theirs.node_id, &theirs.challenge));
+    let answer = client.identify(context::current(), proof).await??;
+    if !theirs
+        .node_id
+        .verify(&identify_message(&ours.node_id, &ours.challenge), &answer)
+    {
+        return Err(NekoError::BadSignature.into());
+    }
+    Ok(features)
+}
@@ -0,0 +1,144 @@
+use std::{
+    fmt, fs,
+    io::{self, ErrorKind, Write},
+    path::Path,
+    str::FromStr,
+};
+
+use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+pub use ed25519_dalek::Signature;
+
+/// Stable identity of a node, the public half of its [Keypair]
+///
+/// The index is keyed by the IDs of registering peers and files record the ID of their origin,
+/// so a peer keeps its registrations and origin files when its address changes.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PeerId([u8; 32]);
+
+impl PeerId {
+    /// ID made up of the raw public key `bytes`
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        PeerId(bytes)
+    }
+
+    /// Raw public key behind this ID
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.0
+    }
+
+    /// Check that `signature` over `message` was made with the key behind this ID
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        VerifyingKey::from_bytes(&self.0).is_ok_and(|key| key.verify(message, signature).is_ok())
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{0}", hex::encode(self.0))
+    }
+}
+
+impl fmt::Debug for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "PeerId({self})")
+    }
+}
+
+impl FromStr for PeerId {
+    type Err = hex::FromHexError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut bytes = [0; 32];
+        hex::decode_to_slice(s, &mut bytes)?;
+        Ok(PeerId(bytes))
+    }
+}
+
+impl Serialize for PeerId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for PeerId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(de::Error::custom)
+    }
+}
+
+/// Long-term ed25519 key of a node, which its [PeerId] is derived from
+pub struct Keypair(SigningKey);
+
+impl Keypair {
+    /// Generate a new random key
+    pub fn generate() -> Self {
+        Keypair(SigningKey::generate(&mut rand::rngs::OsRng))
+    }
+
+    /// Read the hex-encoded key kept at `path`, generating and saving a new one if there is none
+    pub fn load_or_generate(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(x) => {
+                let mut secret = [0; 32];
+                hex::decode_to_slice(x.trim(), &mut secret)
+                    .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))?;
+                Ok(Keypair(SigningKey::from_bytes(&secret)))
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                let keypair = Keypair::generate();
+                let mut options = fs::OpenOptions::new();
+                options.write(true).create_new(true);
+                #[cfg(unix)]
+                std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+                writeln!(
+                    options.open(path)?,
+                    "{0}",
+                    hex::encode(keypair.0.to_bytes())
+                )?;
+                Ok(keypair)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// [PeerId] derived from this key
+    pub fn id(&self) -> PeerId {
+        PeerId(self.0.verifying_key().to_bytes())
+    }
+
+    /// Sign `message` with this key
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        self.0.sign(message)
+    }
+}
+
+/// What a node signs to prove to `verifier` that it holds the key behind its [PeerId], given the
+/// `challenge` the verifier sent in its [crate::Hello]
+pub(crate) fn identify_message(verifier: &PeerId, challenge: &[u8; 32]) -> Vec<u8> {
+    [b"nekop2p identify".as_slice(), &verifier.0, challenge].concat()
+}
+
+/// Write a [Signature] as hex and read it back, for signatures kept in human-readable files
+pub(crate) mod signature_hex {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    use super::Signature;
+
+    pub fn serialize<S: Serializer>(
+        signature: &Signature,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hex::encode(signature.to_bytes()))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Signature, D::Error> {
+        let mut bytes = [0; 64];
+        hex::decode_to_slice(String::deserialize(deserializer)?, &mut bytes)
+            .map_err(de::Error::custom)?;
+        Ok(Signature::from_bytes(&bytes))
+    }
+}
@@ -0,0 +1,391 @@
+use std::{
+    future::Future,
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    path::PathBuf,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use delay_map::HashSetDelay;
+use futures::{future::BoxFuture, prelude::*};
+use tarpc::server::{incoming::Incoming, BaseChannel, Channel};
+use tokio::{
+    sync::{watch, RwLock},
+    task::JoinHandle,
+};
+
+use crate::{
+    Audits, DiskStore, Endpoint, IndexStore, Indexer, IndexerServer, Keypair, Leases, MemoryStore,
+    NetworkKey, PeerId, Security,
+};
+
+/// Number of registrations re-challenged every audit interval of an [IndexerNode]
+const AUDIT_SAMPLE: usize = 16;
+
+/// Resolves once `stopped` is set, but never if its sender is dropped without setting it
+async fn stopping(mut stopped: watch::Receiver<bool>) {
+    if stopped.wait_for(|x| *x).await.is_err() {
+        future::pending().await
+    }
+}
+
+/// Transport that stops reading requests once the [IndexerNode] is told to stop, while still
+/// sending the responses to those already in flight
+struct Draining<T> {
+    /// Transport of the connection
+    inner: T,
+
+    /// Resolves once the indexer is told to stop, taken once it has
+    stop: Option<BoxFuture<'static, ()>>,
+}
+
+impl<T> Draining<T> {
+    /// Wrap `inner` to stop reading once `stopped` is set
+    fn new(inner: T, stopped: watch::Receiver<bool>) -> Self {
+        Draining {
+            inner,
+            stop: Some(Box::pin(stopping(stopped))),
+        }
+    }
+}
+
+impl<T: Stream + Unpin> Stream for Draining<T> {
+    type Item = T::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T::Item>> {
+        let Some(stop) = &mut self.stop else {
+            return Poll::Ready(None);
+        };
+        if stop.as_mut().poll(cx).is_ready() {
+            self.stop = None;
+            return Poll::Ready(None);
+        }
+        Pin::new(&mut self.inner).poll_next(cx)
+    }
+}
+
+impl<T: Sink<I> + Unpin, I> Sink<I> for Draining<T> {
+    type Error = T::Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_ready(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: I) -> Result<(), T::Error> {
+        Pin::new(&mut self.inner).start_send(item)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), T::Error>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// Where an [IndexerNode] keeps its index
+pub enum Storage {
+    /// Keep the index in memory only
+    Memory,
+
+    /// Persist the index in a snapshot file at `path` with its log next to it, compacting the
+    /// log into the snapshot as it grows, and dropping restored registrations whose peer doesn't
+    /// reconnect within `grace`
+    Disk { path: PathBuf, grace: Duration },
+
+    /// Keep the index in the given [IndexStore]
+    Custom(Arc<dyn IndexStore>),
+}
+
+/// Builder for an [IndexerNode], created with [IndexerNode::builder]
+pub struct IndexerNodeBuilder {
+    bind: SocketAddr,
+    neighbors: Vec<SocketAddr>,
+    backtrace_ttl: Duration,
+    lease: Duration,
+    max_connections: usize,
+    max_connections_per_peer: u32,
+    storage: Storage,
+    keypair: Option<Arc<Keypair>>,
+    security: Security,
+    network: Option<NetworkKey>,
+    verify: bool,
+    audit_interval: Option<Duration>,
+}
+
+impl IndexerNodeBuilder {
+    /// Address to listen on (default `127.0.0.1:0`, any free port)
+    pub fn bind(mut self, bind: SocketAddr) -> Self {
+        self.bind = bind;
+        self
+    }
+
+    /// Neighboring indexers queries are forwarded to (default none)
+    pub fn neighbors(mut self, neighbors: Vec<SocketAddr>) -> Self {
+        self.neighbors = neighbors;
+        self
+    }
+
+    /// How long the ids of forwarded queries are remembered (default 10 seconds)
+    pub fn backtrace_ttl(mut self, ttl: Duration) -> Self {
+        self.backtrace_ttl = ttl;
+        self
+    }
+
+    /// How long a peer's registrations last without a heartbeat (default 30 seconds)
+    pub fn lease(mut self, lease: Duration) -> Self {
+        self.lease = lease;
+        self
+    }
+
+    /// Number of connections served at once (default 10)
+    pub fn max_connections(mut self, n: usize) -> Self {
+        self.max_connections = n;
+        self
+    }
+
+    /// Number of connections served at once from the same IP address (default unlimited)
+    pub fn max_connections_per_peer(mut self, n: u32) -> Self {
+        self.max_connections_per_peer = n;
+        self
+    }
+
+    /// Where to keep the index (default [Storage::Memory])
+    pub fn storage(mut self, storage: Storage) -> Self {
+        self.storage = storage;
+        self
+    }
+
+    /// Key the indexer proves its identity with (default a freshly generated one)
+    pub fn keypair(mut self, keypair: &Arc<Keypair>) -> Self {
+        self.keypair = Some(Arc::clone(keypair));
+        self
+    }
+
+    /// How connections to and from the indexer are protected (default
+    /// [Security::Plaintext])
+    pub fn security(mut self, security: Security) -> Self {
+        self.security = security;
+        self
+    }
+
+    /// Only serve and forward to nodes of the private `network` (default any node)
+    pub fn network(mut self, network: NetworkKey) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Challenge every registration to prove its holder has the file before it is listed as
+    /// verified (default false)
+    pub fn verify(mut self, verify: bool) -> Self {
+        self.verify = verify;
+        self
+    }
+
+    /// Re-challenge a random sample of registrations this often, catching holders that lost
+    /// their files since (default never)
+    pub fn audit_interval(mut self, interval: Duration) -> Self {
+        self.audit_interval = Some(interval);
+        self
+    }
+
+    /// Bind the listener and start serving in the background
+    pub async fn start(self) -> io::Result<IndexerNode> {
+        let (store, grace): (Arc<dyn IndexStore>, _) = match self.storage {
+            Storage::Memory => (Arc::new(MemoryStore::default()), None),
+            Storage::Disk { path, grace } => {
+                let disk = Arc::new(DiskStore::open(path)?);
+                (Arc::clone(&disk) as _, Some((disk, grace)))
+            }
+            Storage::Custom(x) => (x, None),
+        };
+        let keypair = self
+            .keypair
+            .unwrap_or_else(|| Arc::new(Keypair::generate()));
+        let mut endpoint = Endpoint::new(&keypair, self.security);
+        if let Some(network) = self.network {
+            endpoint = endpoint.network(network);
+        }
+        let id = keypair.id();
+        let leases = Arc::new(Leases::new(self.lease));
+        let neighbors = Arc::new(self.neighbors);
+        let backtrace = Arc::new(RwLock::new(HashSetDelay::new(self.backtrace_ttl)));
+        let audits = (self.verify || self.audit_interval.is_some())
+            .then(|| Arc::new(Audits::new(&endpoint, self.verify)));
+
+        let listener = endpoint.listen(self.bind).await?;
+        let local_addr = listener.local_addr();
+
+        let (stop, stopped) = watch::channel(false);
+        let served = Arc::clone(&store);
+        let leased = Arc::clone(&leases);
+        let audited = audits.clone();
+        let draining = stopped.clone();
+        let serve = listener
+            // Stop accepting connections once told to stop
+            .take_until(stopping(stopped.clone()))
+            // Establish serve channel, answering what is in flight once told to stop
+            .map(move |transport| {
+                BaseChannel::with_defaults(Draining::new(transport, draining.clone()))
+            })
+            .max_channels_per_key(self.max_connections_per_peer, |channel| {
+                channel.transport().inner.get_ref().peer_addr().ip()
+            })
+            .map(move |channel| {
+                let conn = channel.transport().inner.get_ref();
+                let mut server = IndexerServer::new(
+                    conn.peer_addr(),
+                    conn.remote(),
+                    &endpoint,
+                    &served,
+                    &leased,
+                    &neighbors,
+                    &backtrace,
+                );
+                if let Some(audits) = &audited {
+                    server = server.audits(audits);
+                }
+                channel
+                    .execute(server.serve())
+                    .for_each(|response| async move {
+                        tokio::spawn(response);
+                    })
+            })
+            .buffer_unordered(self.max_connections)
+            .for_each(|_| async {});
+
+        // drop peers that stopped sending heartbeats
+        let reaped = Arc::clone(&store);
+        let forgotten = audits.clone();
+        let reap = async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                for peer in leases.expired() {
+                    println!("Lease of {peer} lapsed, dropping its registrations");
+                    reaped.remove_peer(peer);
+                    if let Some(audits) = &forgotten {
+                        audits.forget_peer(peer);
+                    }
+                }
+            }
+        };
+
+        // drop restored registrations whose peers never came back
+        let expire = async move {
+            if let Some((disk, grace)) = grace {
+                tokio::time::sleep(grace).await;
+                for peer in disk.expire() {
+                    println!("Dropped unconfirmed registrations of {peer}");
+                }
+            }
+        };
+
+        // catch holders that lost or changed their files since they were challenged
+        let sampled = Arc::clone(&store);
+        let audit = async move {
+            let (Some(audits), Some(interval)) = (audits, self.audit_interval) else {
+                return future::pending().await;
+            };
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                audits.sample(&*sampled, AUDIT_SAMPLE).await;
+            }
+        };
+
+        // the serve loop only ends once told to stop and every open connection has drained
+        let task = tokio::spawn(async move {
+            tokio::select! {
+                _ = serve => {}
+                _ = future::join3(reap, expire, audit) => {}
+            }
+        });
+
+        Ok(IndexerNode {
+            id,
+            local_addr,
+            store,
+            stop,
+            task,
+        })
+    }
+}
+
+/// Handle to an [IndexerServer] serving in the background, created through
+/// [IndexerNode::builder]
+///
+/// Awaiting the handle waits until the indexer stops, which happens once
+/// [IndexerNode::shutdown] is called. Dropping the handle leaves the indexer running in the
+/// background.
+pub struct IndexerNode {
+    /// ID the indexer introduces itself with
+    id: PeerId,
+
+    /// Address the indexer is listening on
+    local_addr: SocketAddr,
+
+    /// Index shared between all connections
+    store: Arc<dyn IndexStore>,
+
+    /// Tells the serve loop to stop, which it ignores if dropped
+    stop: watch::Sender<bool>,
+
+    /// Serve loop, lease reaper, grace expiry and audits
+    task: JoinHandle<()>,
+}
+
+impl IndexerNode {
+    /// Start building an [IndexerNode]
+    pub fn builder() -> IndexerNodeBuilder {
+        IndexerNodeBuilder {
+            bind: (Ipv4Addr::LOCALHOST, 0).into(),
+            neighbors: Vec::new(),
+            backtrace_ttl: Duration::from_secs(10),
+            lease: Duration::from_secs(30),
+            max_connections: 10,
+            max_connections_per_peer: u32::MAX,
+            storage: Storage::Memory,
+            keypair: None,
+            security: Security::Plaintext,
+            network: None,
+            verify: false,
+            audit_interval: None,
+        }
+    }
+
+    /// ID the indexer introduces itself with in [Indexer::hello]
+    pub fn id(&self) -> PeerId {
+        self.id
+    }
+
+    /// Address the indexer is listening on
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Index shared between all connections
+    pub fn store(&self) -> &Arc<dyn IndexStore> {
+        &self.store
+    }
+
+    /// Stop accepting connections and reading requests, then close each open connection once
+    /// the requests in flight on it are answered, and stop the background tasks once all of them
+    /// are closed
+    pub fn shutdown(&self) {
+        let _ = self.stop.send(true);
+    }
+}
+
+impl Future for IndexerNode {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.task).poll(cx).map(|_| ())
+    }
+}
@@ -0,0 +1,359 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+
+use crate::{share::sidecar, FileRecord, Hit, Invalidation, PeerId, Query, Verification};
+
+/// Bytes the log may grow to before it is compacted, unless the snapshot is larger still
+const COMPACT_AFTER: u64 = 16 * 1024 * 1024;
+
+/// Registrations of each peer by filename
+type Registrations = HashMap<PeerId, HashMap<String, FileRecord>>;
+
+/// State of the index as kept in the snapshot file
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Snapshot {
+    /// Last address each peer could be downloaded from at
+    addrs: HashMap<PeerId, SocketAddr>,
+
+    /// Files registered by each peer
+    files: Registrations,
+}
+
+/// A single change to the index, as recorded in the log
+#[derive(Deserialize, Serialize)]
+enum Entry {
+    /// `peer` can be downloaded from at `addr`
+    Address { peer: PeerId, addr: SocketAddr },
+
+    /// `peer` registered the file described by `record`
+    Register { record: FileRecord, peer: PeerId },
+
+    /// `peer` deregistered `filename`
+    Deregister { filename: String, peer: PeerId },
+
+    /// `peer` left the index entirely
+    Disconnect { peer: PeerId },
+}
+
+/// Durable record of an indexer's registrations
+///
+/// State is kept as a snapshot file plus an append-only log of changes (`<snapshot>.log`). On
+/// [Journal::open], the log is replayed on top of the snapshot and compacted into a new snapshot.
+/// While open, the log is compacted again whenever it grows past [COMPACT_AFTER] bytes or the
+/// size of the snapshot, whichever is larger.
+///
+/// Restored registrations stay *unconfirmed* until their peer reconnects under the same
+/// [PeerId] and calls [crate::Indexer::set_port], from any address, or until they are dropped
+/// with [Journal::expire].
+pub struct Journal {
+    /// Path of the snapshot file
+    path: PathBuf,
+
+    /// Append-only log of changes since the snapshot was taken
+    log: Mutex<Log>,
+
+    /// Bytes the log may grow to before it is compacted, unless the snapshot is larger still
+    compact_after: u64,
+
+    /// Restored registrations whose peers haven't reconnected yet
+    unconfirmed: DashMap<PeerId, DashMap<String, FileRecord>>,
+
+    /// Last known address of each peer with unconfirmed registrations
+    addrs: DashMap<PeerId, SocketAddr>,
+}
+
+/// Log file along with the state it brings the snapshot to
+struct Log {
+    /// Append-only log of changes since the snapshot was taken
+    file: File,
+
+    /// Snapshot with every logged change applied
+    state: Snapshot,
+
+    /// Bytes written to the log since the snapshot was taken
+    written: u64,
+
+    /// Size of the snapshot file
+    snapshot_size: u64,
+}
+
+/// Write `snapshot` to `path` and truncate its log, returning the new log along with the size
+/// of the snapshot
+fn compact(path: &Path, snapshot: &Snapshot) -> io::Result<(File, u64)> {
+    // write the snapshot before truncating the log, since replaying the log again is harmless
+    let text = serde_json::to_string(snapshot)?;
+    let tmp_path: PathBuf = sidecar(path, ".tmp");
+    fs::write(&tmp_path, &text)?;
+    fs::rename(&tmp_path, path)?;
+    let log = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(sidecar(path, ".log"))?;
+    Ok((log, text.len() as u64))
+}
+
+/// Apply `entry` to `snapshot`
+fn apply(snapshot: &mut Snapshot, entry: Entry) {
+    let registrations = &mut snapshot.files;
+    match entry {
+        Entry::Address { peer, addr } => {
+            snapshot.addrs.insert(peer, addr);
+        }
+        Entry::Register { record, peer } => {
+            registrations
+                .entry(peer)
+                .or_default()
+                .insert(record.filename.clone(), record);
+        }
+        Entry::Deregister { filename, peer } => {
+            if let Some(files) = registrations.get_mut(&peer) {
+                files.remove(&filename);
+                if files.is_empty() {
+                    registrations.remove(&peer);
+                }
+            }
+        }
+        Entry::Disconnect { peer } => {
+            registrations.remove(&peer);
+            snapshot.addrs.remove(&peer);
+        }
+    }
+}
+
+impl Journal {
+    /// Open the journal with its snapshot at `path`, restoring its registrations as unconfirmed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let log_path = sidecar(path, ".log");
+
+        // start from the last snapshot
+        let mut snapshot: Snapshot = match fs::read_to_string(path) {
+            Ok(x) => serde_json::from_str(&x)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Snapshot::default(),
+            Err(e) => return Err(e),
+        };
+
+        // then replay everything logged since, stopping at a torn final write
+        if let Ok(log) = File::open(&log_path) {
+            for line in BufReader::new(log).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(entry) => apply(&mut snapshot, entry),
+                    Err(_) => break,
+                }
+            }
+        }
+
+        // compact into a fresh snapshot, forgetting the addresses of peers without files
+        let files = &snapshot.files;
+        snapshot.addrs.retain(|peer, _| files.contains_key(peer));
+        let (file, snapshot_size) = compact(path, &snapshot)?;
+
+        Ok(Journal {
+            path: path.to_owned(),
+            log: Mutex::new(Log {
+                file,
+                state: snapshot.clone(),
+                written: 0,
+                snapshot_size,
+            }),
+            compact_after: COMPACT_AFTER,
+            unconfirmed: snapshot
+                .files
+                .into_iter()
+                .map(|(peer, files)| (peer, files.into_iter().collect()))
+                .collect(),
+            addrs: snapshot.addrs.into_iter().collect(),
+        })
+    }
+
+    /// Claim the unconfirmed registrations of `peer`
+    pub fn confirm(&self, peer: PeerId) -> Vec<FileRecord> {
+        self.addrs.remove(&peer);
+        self.unconfirmed
+            .remove(&peer)
+            .map(|(_, files)| files.into_iter().map(|(_, x)| x).collect())
+            .unwrap_or_default()
+    }
+
+    /// Unconfirmed peers holding `filename`, with the last address they could be downloaded from
+    pub fn unconfirmed_holders(&self, filename: &str) -> Vec<(PeerId, SocketAddr)> {
+        self.unconfirmed
+            .iter()
+            .filter(|e| e.value().contains_key(filename))
+            .filter_map(|e| Some((*e.key(), *self.addrs.get(e.key())?)))
+            .collect()
+    }
+
+    /// Unconfirmed peers holding a filename matching `query`
+    pub fn unconfirmed_matches(&self, query: &Query) -> Vec<Hit> {
+        self.unconfirmed
+            .iter()
+            .flat_map(|e| {
+                let holder = *e.key();
+                let Some(peer) = self.addrs.get(&holder).map(|x| *x) else {
+                    return Vec::new();
+                };
+                e.value()
+                    .iter()
+                    .filter(|x| query.matches(x.key()))
+                    .map(|x| Hit {
+                        record: x.value().clone(),
+                        holder,
+                        peer,
+                        verification: Verification::Unverified,
+                    })
+                    .collect()
+            })
+            .collect()
+    }
+
+    /// Forget unconfirmed registrations superseded by `invalidation`
+    pub fn drop_superseded(&self, invalidation: &Invalidation) {
+        let filename = &invalidation.filename;
+        for entry in self.unconfirmed.iter() {
+            let holder = *entry.key();
+            if entry
+                .value()
+                .remove_if(filename, |_, x| invalidation.supersedes(holder, x))
+                .is_some()
+            {
+                self.deregister(filename, holder);
+            }
+        }
+    }
+
+    /// Drop every registration that is still unconfirmed, returning the peers that were dropped
+    pub fn expire(&self) -> Vec<PeerId> {
+        let peers: Vec<_> = self.unconfirmed.iter().map(|e| *e.key()).collect();
+        for peer in peers.iter() {
+            self.unconfirmed.remove(peer);
+            self.addrs.remove(peer);
+            self.disconnect(*peer);
+        }
+        peers
+    }
+
+    /// Prints all unconfirmed entries
+    pub fn print_unconfirmed(&self) {
+        self.unconfirmed.iter().for_each(|entry| {
+            let peer = entry.key();
+            entry.value().iter().for_each(|filename| {
+                println!("{0}: {peer} (unconfirmed)", filename.key());
+            });
+        });
+    }
+
+    /// Append `entry` to the log, compacting it into the snapshot once it grew too large
+    fn append(&self, entry: Entry) {
+        let mut log = self.log.lock().unwrap();
+        let result = serde_json::to_string(&entry)
+            .map_err(io::Error::from)
+            .and_then(|line| writeln!(log.file, "{line}").map(|_| line.len() as u64 + 1));
+        match result {
+            Ok(n) => log.written += n,
+            Err(e) => println!("Failed to write to journal: {e}"),
+        }
+        apply(&mut log.state, entry);
+
+        if log.written > self.compact_after.max(log.snapshot_size) {
+            match compact(&self.path, &log.state) {
+                Ok((file, snapshot_size)) => {
+                    log.file = file;
+                    log.written = 0;
+                    log.snapshot_size = snapshot_size;
+                }
+                Err(e) => println!("Failed to compact journal: {e}"),
+            }
+        }
+    }
+
+    /// Record that `peer` can be downloaded from at `addr`
+    pub fn set_addr(&self, peer: PeerId, addr: SocketAddr) {
+        self.append(Entry::Address { peer, addr });
+    }
+
+    /// Record that `peer` registered the file described by `record`
+    pub fn register(&self, record: &FileRecord, peer: PeerId) {
+        self.append(Entry::Register {
+            record: record.clone(),
+            peer,
+        });
+    }
+
+    /// Record that `peer` deregistered `filename`
+    pub fn deregister(&self, filename: &str, peer: PeerId) {
+        self.append(Entry::Deregister {
+            filename: filename.to_owned(),
+            peer,
+        });
+    }
+
+    /// Record that `peer` left the index
+    pub fn disconnect(&self, peer: PeerId) {
+        self.append(Entry::Disconnect { peer });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use super::*;
+    use crate::Keypair;
+
+    /// Record of `filename` published by `origin`
+    fn record(filename: &str, origin: PeerId) -> FileRecord {
+        FileRecord {
+            filename: filename.to_owned(),
+            size: 0,
+            digest: String::new(),
+            version: 0,
+            origin,
+            modified: SystemTime::UNIX_EPOCH,
+            readers: None,
+        }
+    }
+
+    #[test]
+    fn log_is_compacted_while_open() {
+        let path = std::env::temp_dir().join(format!("nekop2p-journal-{0}", std::process::id()));
+        let (alice, bob) = (Keypair::generate().id(), Keypair::generate().id());
+        let addr = "127.0.0.1:5001".parse().unwrap();
+
+        let mut journal = Journal::open(&path).unwrap();
+        journal.compact_after = 1024;
+        journal.set_addr(alice, addr);
+        journal.set_addr(bob, addr);
+        for i in 0..100 {
+            journal.register(&record(&format!("churn-{i}"), bob), bob);
+            journal.deregister(&format!("churn-{i}"), bob);
+        }
+        journal.register(&record("kept", alice), alice);
+        journal.register(&record("left", bob), bob);
+        journal.disconnect(bob);
+
+        // the log never grows far past the limit
+        let logged = fs::metadata(sidecar(&path, ".log")).unwrap().len();
+        assert!(logged <= 2048, "{logged}");
+        drop(journal);
+
+        let journal = Journal::open(&path).unwrap();
+        assert_eq!(journal.unconfirmed_holders("kept"), [(alice, addr)]);
+        assert!(journal.unconfirmed_holders("left").is_empty());
+        assert!(journal.unconfirmed_holders("churn-99").is_empty());
+        assert!(journal.confirm(bob).is_empty());
+
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(sidecar(&path, ".log")).unwrap();
+    }
+}
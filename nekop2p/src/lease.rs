@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+use dashmap::DashMap;
+
+use crate::PeerId;
+
+/// Registration leases of the peers connected to an [crate::IndexerServer]
+///
+/// A lease is granted when a peer sets its port or registers a file, and renewed through
+/// [crate::Indexer::heartbeat]. Peers whose lease lapses are returned by [Leases::expired] so
+/// their registrations can be dropped.
+pub struct Leases {
+    /// How long a lease lasts without being renewed
+    length: Duration,
+
+    /// When each peer's lease runs out
+    expiry: DashMap<PeerId, Instant>,
+}
+
+impl Leases {
+    /// Create an empty set of leases lasting `length` each
+    pub fn new(length: Duration) -> Self {
+        Leases {
+            length,
+            expiry: DashMap::new(),
+        }
+    }
+
+    /// How long a lease lasts without being renewed
+    pub fn length(&self) -> Duration {
+        self.length
+    }
+
+    /// Grant `peer` a fresh lease, replacing any it already holds
+    pub fn grant(&self, peer: PeerId) {
+        self.expiry.insert(peer, Instant::now() + self.length);
+    }
+
+    /// Extend the lease of `peer`, returning false if it doesn't hold one
+    pub fn renew(&self, peer: PeerId) -> bool {
+        self.expiry
+            .get_mut(&peer)
+            .map(|mut x| *x = Instant::now() + self.length)
+            .is_some()
+    }
+
+    /// Give up the lease of `peer`
+    pub fn release(&self, peer: PeerId) {
+        self.expiry.remove(&peer);
+    }
+
+    /// Remove every lease that has run out, returning the peers that held them
+    pub fn expired(&self) -> Vec<PeerId> {
+        let now = Instant::now();
+        let lapsed: Vec<_> = self
+            .expiry
+            .iter()
+            .filter(|e| *e.value() <= now)
+            .map(|e| *e.key())
+            .collect();
+
+        // a heartbeat may have come in since
+        lapsed
+            .into_iter()
+            .filter(|peer| self.expiry.remove_if(peer, |_, x| *x <= now).is_some())
+            .collect()
+    }
+}
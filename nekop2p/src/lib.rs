@@ -1,59 +1,187 @@
 //! Common library for nekop2p
 //!
 //! Contains the schemas for RPC between peers and for the indexer. Service traits are defined as
-//! [Peer] and [Indexer].
+//! [Peer] and [Indexer], along with [Control] for driving a peer daemon over its local socket.
 //!
 //! Both a peer and indexer reference server are provided in [PeerServer] and [IndexerServer]
-//! respectively.
+//! respectively. The index behind an [IndexerServer] is pluggable through [IndexStore], and
+//! [IndexerNode] runs one on a listener in the background, optionally challenging holders to prove
+//! they have the files they register (see [Audits]).
+//! [PeerNode] wraps a [PeerServer] and an [IndexerClient] into a peer that can be embedded in
+//! other programs.
 //!
-//! Clients are utilized using [tarpc]'s generated [PeerClient] and [IndexerClient].
+//! Connections start with a [Hello] exchange through [Indexer::hello] or [Peer::hello], so
+//! nodes of incompatible releases refuse each other and optional [Features] are only used when
+//! both sides support them. Every node is identified by the [PeerId] of its long-term [Keypair],
+//! which peers prove to their indexer through [Indexer::identify]. Origins sign each [Metadata]
+//! version and [Invalidation] with the same key. Requests an [Indexer] or [Peer] turns down fail
+//! with a [NekoError] giving the reason.
+//!
+//! Connections are made through an [Endpoint], which can optionally encrypt and mutually
+//! authenticate them with Noise (see [Security]) and keep out nodes that aren't members of the
+//! same private network (see [NetworkKey]).
+//!
+//! Clients are utilized using [tarpc]'s generated [PeerClient], [IndexerClient] and
+//! [ControlClient].
+mod audit;
+mod control;
+mod digest;
+mod download;
+mod error;
+mod hello;
+mod identity;
+mod indexer;
+mod journal;
+mod lease;
+mod network;
+mod node;
+mod partial;
 mod peer;
+mod query;
+mod record;
 mod server;
-pub use peer::{Metadata, PeerServer};
+mod share;
+mod store;
+mod transport;
+pub use audit::{Audits, Verification};
+pub use control::{NodeStatus, Status, CONTROL_SOCKET};
+pub use digest::{hash_file, piece_count, verify_piece, FileDigest, Hash, MerkleTree};
+pub use error::NekoError;
+pub use hello::{handshake, Features, Hello, PROTOCOL_VERSION};
+pub use identity::{Keypair, PeerId, Signature};
+pub use indexer::{IndexerNode, IndexerNodeBuilder, Storage};
+pub use lease::Leases;
+pub use network::NetworkKey;
+pub use node::{NodeError, NodeEvent, PeerNode, Synced};
+pub use peer::{Invalidation, Metadata, PeerServer, TreeCache};
+pub use query::{format_hits, Hit, ParseQueryError, Query};
+pub use record::FileRecord;
 pub use server::IndexerServer;
-
-use std::net::SocketAddr;
+pub use share::{sidecar, ShareRoot};
+pub use store::{DiskStore, IndexStore, MemoryStore};
+pub use transport::{Connection, Endpoint, Listener, Security, Transport};
 
 use uuid::Uuid;
 
+/// Largest chunk a [PeerServer] will send in a single [Peer::read_chunk] response
+pub const CHUNK_SIZE: u32 = 256 * 1024;
+
 /// RPC scheme for interacting with an [IndexerServer]
 #[tarpc::service]
 pub trait Indexer {
-    /// Map the IP address of this peer to `dl_port` as the corrisponding incoming port to connect
-    /// to if another peer wishes to download from this peer.
-    async fn set_port(dl_port: u16);
+    /// Exchange [Hello]s with the indexer, which refuses peers with an incompatible
+    /// [PROTOCOL_VERSION]. Kept first so every release decodes it the same way.
+    async fn hello(hello: Hello) -> Result<Hello, NekoError>;
+
+    /// Prove that this peer holds the key behind the [PeerId] it sent with [Indexer::hello] by
+    /// `signature` over the indexer's challenge, and get the indexer's signature over this peer's
+    /// challenge in return. Every request that changes the index requires it.
+    async fn identify(signature: Signature) -> Result<Signature, NekoError>;
+
+    /// Record the IP address of this peer with `dl_port` as the address to connect to if another
+    /// peer wishes to download from this peer. Addresses are only reachability hints, the index
+    /// itself is keyed by [PeerId].
+    async fn set_port(dl_port: u16) -> Result<(), NekoError>;
 
-    /// Register `filename` in index
-    async fn register(filename: String);
+    /// Renew this peer's lease, returning its length in seconds, or [NekoError::LeaseLapsed] if
+    /// it already lapsed and the peer has to call [Indexer::set_port] and register its files again
+    async fn heartbeat() -> Result<u64, NekoError>;
 
-    /// Query `filename` in index and returns a [Vec] of [SocketAddr] with the
-    /// connection details for all peers which have `filename`
-    async fn search(filename: String) -> Vec<SocketAddr>;
+    /// Register the file described by `record` in index, which requires [Indexer::set_port] to
+    /// have been called first
+    async fn register(record: FileRecord) -> Result<(), NekoError>;
+
+    /// Query index for filenames matching `query` and returns a [Hit] with the [FileRecord],
+    /// connection details and [Verification] status of every peer holding each of them that this
+    /// peer may download from
+    async fn search(query: Query) -> Vec<Hit>;
 
     /// Deregister `filename` in index
-    async fn deregister(filename: String);
+    async fn deregister(filename: String) -> Result<(), NekoError>;
+
+    /// Register every file described in `records` in index at once, or none of them if any
+    /// can't be registered
+    async fn register_many(records: Vec<FileRecord>) -> Result<(), NekoError>;
+
+    /// Deregister every one of `filenames` in index at once
+    async fn deregister_many(filenames: Vec<String>) -> Result<(), NekoError>;
 
-    /// Remove all mentions of peer from index and dl_ports
+    /// Remove all mentions of peer from index and dl_ports, and give up its lease
     async fn disconnect_peer();
 
-    /// Queries entire network for `filename` with a given ttl
-    async fn query(msg_id: Uuid, filename: String, ttl: u8) -> Vec<SocketAddr>;
+    /// Queries entire network for filenames matching `query` with a given ttl, only listing
+    /// holders restricted to certain readers if this peer identified itself as one of them
+    async fn query(msg_id: Uuid, query: Query, ttl: u8) -> Vec<Hit>;
 
-    /// Spreads an invalidation message across the network for `filename` owned by `origin_server`
+    /// Queries entire network for filenames matching `query` with a given ttl on behalf of
+    /// `requester`, a peer connected to the relaying indexer, which has to identify itself
+    /// through [Indexer::identify] (Indexer endpoint)
+    async fn relay(
+        msg_id: Uuid,
+        query: Query,
+        ttl: u8,
+        requester: Option<PeerId>,
+    ) -> Result<Vec<Hit>, NekoError>;
+
+    /// Spreads an [Invalidation] signed by the origin of its file across the network
     /// (Peer endpoint)
-    async fn invalidate(msg_id: Uuid, origin_server: SocketAddr, filename: String);
+    async fn invalidate(msg_id: Uuid, invalidation: Invalidation) -> Result<(), NekoError>;
 }
 
 /// RPC scheme for interacting with a [PeerServer]
 #[tarpc::service]
 pub trait Peer {
-    /// Query `filename` and send over the raw bytes if it exists
-    async fn download_file(filename: String) -> Option<Vec<u8>>;
+    /// Exchange [Hello]s with the peer, which refuses peers with an incompatible
+    /// [PROTOCOL_VERSION]. Kept first so every release decodes it the same way.
+    async fn hello(hello: Hello) -> Result<Hello, NekoError>;
+
+    /// Query the size of `filename` in bytes
+    async fn file_size(filename: String) -> Result<u64, NekoError>;
 
-    /// Invalidates a `filename` on endpoint, discarding if request is from the
-    /// origin
-    async fn invalidate(msg_id: Uuid, origin_server: SocketAddr, filename: String);
+    /// Read up to `len` bytes of `filename` starting at `offset`, capped at [CHUNK_SIZE], and
+    /// compress them with LZ4 if `compress` is set (requires [Features::COMPRESSION])
+    async fn read_chunk(
+        filename: String,
+        offset: u64,
+        len: u32,
+        compress: bool,
+    ) -> Result<Vec<u8>, NekoError>;
+
+    /// Invalidates a file on endpoint, discarding it if the [Invalidation] is signed by its
+    /// origin and names a newer version
+    async fn invalidate(msg_id: Uuid, invalidation: Invalidation) -> Result<(), NekoError>;
 
     /// Poll file metadata
-    async fn get_metadata(filename: String) -> Option<Metadata>;
+    async fn get_metadata(filename: String) -> Result<Metadata, NekoError>;
+
+    /// Sibling hashes proving that piece number `piece` of `filename` belongs to the Merkle root
+    /// in its [Metadata]
+    async fn get_proof(filename: String, piece: u64) -> Result<Vec<Hash>, NekoError>;
+
+    /// Prove that this peer holds the key behind the [PeerId] it sent with [Peer::hello] by
+    /// signing the challenge of the [Hello] it was answered with, so it can read files restricted
+    /// to it over a plaintext connection, and get the serving peer's signature over this peer's
+    /// challenge in return
+    async fn identify(signature: Signature) -> Result<Signature, NekoError>;
+}
+
+/// RPC scheme for controlling a peer daemon over its local socket
+#[tarpc::service]
+pub trait Control {
+    /// Register `filename` from the daemon's share directories with its indexer
+    async fn register(filename: String) -> Status;
+
+    /// Download `filename` into the daemon's share directories (from every peer holding it at
+    /// once if `swarm` is set) and seed it
+    async fn download(filename: String, swarm: bool) -> Status;
+
+    /// Search the whole network (or only the daemon's indexer if `local` is set) for files
+    /// matching `query`, or [None] if the search failed
+    async fn search(query: Query, local: bool) -> Option<Vec<Hit>>;
+
+    /// Report what the daemon is connected to and sharing
+    async fn status() -> NodeStatus;
+
+    /// Deregister the daemon's files and stop it
+    async fn shutdown();
 }
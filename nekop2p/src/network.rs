@@ -0,0 +1,140 @@
+//! Membership of a private network, proven with a shared secret when a connection is set up
+use std::io::{self, ErrorKind};
+
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Key every node of a private network holds, derived from the network's shared secret
+///
+/// Nodes with an [crate::Endpoint] set to a [NetworkKey] only talk to nodes proving they hold
+/// the same key, so separate networks can't join each other by pointing at their addresses.
+#[derive(Clone)]
+pub struct NetworkKey([u8; 32]);
+
+impl NetworkKey {
+    /// Derive the key of the network whose nodes share `secret`
+    pub fn from_secret(secret: &str) -> Self {
+        NetworkKey(
+            Sha256::new()
+                .chain_update(b"nekop2p network")
+                .chain_update(secret)
+                .finalize()
+                .into(),
+        )
+    }
+
+    /// Tag proving the key is held by the side acting as `role`, bound to the nonces of the
+    /// `initiator` and `responder`
+    fn tag(&self, role: &[u8], initiator: &[u8; 32], responder: &[u8; 32]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).expect("any key length works");
+        mac.update(role);
+        mac.update(initiator);
+        mac.update(responder);
+        mac
+    }
+
+    /// Prove to the node on the other end of `stream` that this node holds the key and check
+    /// that it does too, with the side that connected as the `initiator`
+    ///
+    /// Fresh nonces from both sides go into each tag, so tags seen on earlier connections can't
+    /// be replayed.
+    pub(crate) async fn admit<S>(&self, stream: &mut S, initiator: bool) -> io::Result<()>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let refused = |x| io::Error::new(ErrorKind::PermissionDenied, x);
+        let mut nonce = [0; 32];
+        rand::RngCore::fill_bytes(&mut rand::thread_rng(), &mut nonce);
+        let (mut theirs, mut tag) = ([0; 32], [0; 32]);
+
+        if initiator {
+            // -> nonce
+            stream.write_all(&nonce).await?;
+
+            // <- nonce, responder tag
+            stream.read_exact(&mut theirs).await?;
+            stream.read_exact(&mut tag).await?;
+            self.tag(b"responder", &nonce, &theirs)
+                .verify_slice(&tag)
+                .map_err(|_| refused("other side is not a member of this network"))?;
+
+            // -> initiator tag
+            let ours = self.tag(b"initiator", &nonce, &theirs).finalize();
+            stream.write_all(&ours.into_bytes()).await?;
+
+            // <- admitted
+            if stream.read_u8().await.is_err() {
+                return Err(refused("not admitted to the network of the other side"));
+            }
+        } else {
+            // -> nonce
+            stream.read_exact(&mut theirs).await?;
+
+            // <- nonce, responder tag
+            let ours = self.tag(b"responder", &theirs, &nonce).finalize();
+            stream
+                .write_all(&[nonce.as_slice(), &ours.into_bytes()].concat())
+                .await?;
+
+            // -> initiator tag
+            stream.read_exact(&mut tag).await?;
+            self.tag(b"initiator", &theirs, &nonce)
+                .verify_slice(&tag)
+                .map_err(|_| refused("other side is not a member of this network"))?;
+
+            // <- admitted
+            stream.write_u8(1).await?;
+        }
+        stream.flush().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Run [NetworkKey::admit] between an `initiator` and a `responder` over an in-memory stream
+    async fn admit(initiator: &str, responder: &str) -> (io::Result<()>, io::Result<()>) {
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let (initiator, responder) = (
+            NetworkKey::from_secret(initiator),
+            NetworkKey::from_secret(responder),
+        );
+        // each side hangs up once it is done, like a dropped connection
+        futures::join!(
+            async move { initiator.admit(&mut a, true).await },
+            async move { responder.admit(&mut b, false).await },
+        )
+    }
+
+    #[tokio::test]
+    async fn same_key_is_admitted() {
+        let (initiator, responder) = admit("secret", "secret").await;
+        initiator.unwrap();
+        responder.unwrap();
+    }
+
+    #[tokio::test]
+    async fn other_key_is_refused() {
+        let (initiator, responder) = admit("secret", "other secret").await;
+        assert_eq!(initiator.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(responder.is_err());
+    }
+
+    #[tokio::test]
+    async fn forged_initiator_tag_is_refused() {
+        let (mut a, mut b) = tokio::io::duplex(256);
+        let key = NetworkKey::from_secret("secret");
+        let forger = async move {
+            a.write_all(&[7; 32]).await?;
+            a.read_exact(&mut [0; 64]).await?;
+            a.write_all(&[0; 32]).await?;
+            a.read_u8().await
+        };
+        let (forged, responder) =
+            futures::join!(forger, async move { key.admit(&mut b, false).await });
+        assert_eq!(responder.unwrap_err().kind(), ErrorKind::PermissionDenied);
+        assert!(forged.is_err());
+    }
+}
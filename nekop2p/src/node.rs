@@ -0,0 +1,874 @@
+use std::{
+    collections::BTreeSet,
+    error::Error,
+    fmt, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    slice,
+    sync::Arc,
+    time::Duration,
+};
+
+use dashmap::DashSet;
+use futures::prelude::*;
+use rand::seq::SliceRandom;
+use tarpc::{
+    client::RpcError,
+    context,
+    server::{BaseChannel, Channel},
+};
+use tokio::fs;
+use uuid::Uuid;
+
+use crate::{
+    download::{connect_peer, download_resumable, download_swarm},
+    hash_file,
+    share::is_sidecar,
+    sidecar, Endpoint, Features, FileDigest, FileRecord, Hit, IndexerClient, Invalidation, Keypair,
+    Metadata, NekoError, Peer, PeerId, PeerServer, Query, ShareRoot, Status, TreeCache,
+    Verification,
+};
+
+/// Error returned by the operations of a [PeerNode]
+#[derive(Debug)]
+pub enum NodeError {
+    /// The file is not inside the share directories
+    NotShared(String),
+
+    /// The name can't be downloaded to inside the share directories
+    InvalidName(String),
+
+    /// No peer holds the file
+    NoPeers(String),
+
+    /// A replica no longer matches the version published by its origin
+    Outdated(String),
+
+    /// The file is at the last version number, so no newer version can be published
+    NoVersionsLeft(String),
+
+    /// A piece failed verification against the Merkle root of its file
+    BadPiece(u64),
+
+    /// Downloading from the peers holding a file failed
+    Transfer(String),
+
+    /// The indexer or a peer turned down a request
+    Refused(NekoError),
+
+    /// The indexer or peer at the given address didn't answer [crate::Indexer::hello] or
+    /// [crate::Peer::hello]
+    NoHello(String),
+
+    /// A request to the indexer or a peer failed
+    Rpc(RpcError),
+
+    /// Reading or writing a local file failed
+    Io(io::Error),
+}
+
+impl fmt::Display for NodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeError::NotShared(x) => write!(f, "{x} is not a file in the share root"),
+            NodeError::InvalidName(x) => write!(f, "{x} is not a valid name to download to"),
+            NodeError::NoPeers(x) => write!(f, "no peers to download {x} from"),
+            NodeError::Outdated(x) => {
+                write!(f, "{x} no longer matches the version from its origin")
+            }
+            NodeError::NoVersionsLeft(x) => {
+                write!(
+                    f,
+                    "{x} is at the last version number, publish it under a new name"
+                )
+            }
+            NodeError::BadPiece(x) => {
+                write!(
+                    f,
+                    "piece {x} failed verification, corrupt or malicious peer"
+                )
+            }
+            NodeError::Transfer(x) => write!(f, "{x}"),
+            NodeError::Refused(e) => write!(f, "{e}"),
+            NodeError::NoHello(x) => {
+                write!(
+                    f,
+                    "{x} did not answer hello, it may run an older nekop2p release"
+                )
+            }
+            NodeError::Rpc(e) => write!(f, "request failed: {e}"),
+            NodeError::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl Error for NodeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NodeError::Refused(e) => Some(e),
+            NodeError::Rpc(e) => Some(e),
+            NodeError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<NekoError> for NodeError {
+    fn from(e: NekoError) -> Self {
+        NodeError::Refused(e)
+    }
+}
+
+impl From<RpcError> for NodeError {
+    fn from(e: RpcError) -> Self {
+        NodeError::Rpc(e)
+    }
+}
+
+impl From<io::Error> for NodeError {
+    fn from(e: io::Error) -> Self {
+        NodeError::Io(e)
+    }
+}
+
+impl From<&NodeError> for Status {
+    fn from(e: &NodeError) -> Self {
+        match e {
+            NodeError::NotShared(_)
+            | NodeError::NoPeers(_)
+            | NodeError::Refused(NekoError::NotFound(_) | NekoError::NotRegistered(_)) => {
+                Status::NotFound
+            }
+            _ => Status::Failed,
+        }
+    }
+}
+
+/// Something a [PeerNode] did or ran into outside of the result of a call, reported through
+/// [PeerNode::on_event]
+#[derive(Debug)]
+pub enum NodeEvent {
+    /// Versions of the file older than the one just published were invalidated across the network
+    Invalidated(String),
+
+    /// Invalidating older versions of the file failed, so their replicas are only dropped once
+    /// they poll the origin
+    InvalidateFailed(String, NodeError),
+
+    /// The replica of the file is being polled for validity against its origin
+    Polling(String),
+
+    /// The replica of the file was removed, since it no longer matches its origin
+    Removed(String, NodeError),
+
+    /// The file is being downloaded from the peer at the given address
+    Downloading(String, SocketAddr),
+
+    /// The file is being downloaded from the given number of peers at once
+    Swarming(String, usize),
+
+    /// A partial download of the file was resumed with the given bytes of its size already
+    /// received
+    Resuming(String, u64, u64),
+
+    /// The peer at the given address failed to serve the file, or a piece of it
+    PeerFailed(String, SocketAddr, NodeError),
+
+    /// The peer at the given address stopped serving pieces of a swarmed file after serving the
+    /// given number of them
+    Served(String, SocketAddr, usize),
+
+    /// The lease on the indexer lapsed, so every file is registered again
+    LeaseLapsed,
+
+    /// Registering files again after the lease lapsed failed
+    ReregisterFailed(NodeError),
+
+    /// The file couldn't be registered again after the lease lapsed
+    NotShared(String, NodeError),
+
+    /// The indexer didn't accept a heartbeat
+    HeartbeatFailed(NodeError),
+}
+
+impl fmt::Display for NodeEvent {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NodeEvent::Invalidated(x) => {
+                write!(f, "Sent invalidation message for older versions of {x}")
+            }
+            NodeEvent::InvalidateFailed(x, e) => {
+                write!(f, "Failed to invalidate older versions of {x}: {e}")
+            }
+            NodeEvent::Polling(x) => write!(f, "Polling validity of {x}..."),
+            NodeEvent::Removed(x, e) => write!(f, "Removed replica of {x}: {e}"),
+            NodeEvent::Downloading(x, peer) => write!(f, "Downloading {x} from {peer}..."),
+            NodeEvent::Swarming(x, n) => write!(f, "Swarming {x} from {n} peers..."),
+            NodeEvent::Resuming(x, received, size) => {
+                write!(
+                    f,
+                    "Resuming {x} with {received} of {size} bytes already downloaded"
+                )
+            }
+            NodeEvent::PeerFailed(x, peer, e) => write!(f, "Peer {peer} failed {x}: {e}"),
+            NodeEvent::Served(x, peer, n) => write!(f, "Peer {peer} served {n} pieces of {x}"),
+            NodeEvent::LeaseLapsed => write!(f, "Lease on indexer lapsed, registering files again"),
+            NodeEvent::ReregisterFailed(e) => write!(f, "Failed to register files again: {e}"),
+            NodeEvent::NotShared(x, e) => write!(f, "Not sharing {x}: {e}"),
+            NodeEvent::HeartbeatFailed(e) => write!(f, "Heartbeat to indexer failed: {e}"),
+        }
+    }
+}
+
+/// Hook a [PeerNode] reports its [NodeEvent]s to
+pub(crate) type EventHook = dyn Fn(&NodeEvent) + Send + Sync;
+
+/// Outcome of a [PeerNode::sync]
+#[derive(Debug, Default)]
+pub struct Synced {
+    /// Files registered with the indexer
+    pub registered: Vec<FileRecord>,
+
+    /// Files that couldn't be shared, along with why
+    pub skipped: Vec<(String, NodeError)>,
+}
+
+/// Metadata for `filename` now that it hashes to `hashed` and may be downloaded by `readers`,
+/// signed by `keypair`, bumping the version of its `previous` metadata or starting a new file
+/// with `keypair` as its origin
+///
+/// Only the origin can sign new versions, so a changed replica becomes a new file. Fails once the
+/// version number can't go any higher, since replicas would refuse a wrapped-around version.
+fn publish_metadata(
+    previous: Option<Metadata>,
+    hashed: &FileDigest,
+    readers: Option<BTreeSet<PeerId>>,
+    keypair: &Keypair,
+    filename: &str,
+    ttr: u8,
+) -> Result<Metadata, NodeError> {
+    match previous {
+        // increment version since we're updating this file
+        Some(x) if x.origin == keypair.id() => {
+            let version = x
+                .version
+                .checked_add(1)
+                .ok_or_else(|| NodeError::NoVersionsLeft(filename.to_owned()))?;
+            Ok(Metadata::new(
+                keypair, filename, version, x.ttr, hashed, readers,
+            ))
+        }
+        // not ours or not found, make new metadata file instead with an initial version of zero
+        _ => Ok(Metadata::new(keypair, filename, 0, ttr, hashed, readers)),
+    }
+}
+
+/// Describe the file at `path` in `share` registered as `filename` for the [crate::Indexer],
+/// along with who may read it
+async fn file_record(
+    share: &ShareRoot,
+    filename: &str,
+    path: &Path,
+    metadata: &Metadata,
+) -> io::Result<FileRecord> {
+    let modified = fs::metadata(path).await?.modified()?;
+    let mut record = FileRecord::new(filename.to_owned(), metadata, modified);
+    record.readers = metadata.readers_in(share, path);
+    Ok(record)
+}
+
+/// Remove the replica at `path` along with its metadata
+async fn remove_replica(path: &Path) {
+    let _ = fs::remove_file(path).await;
+    let _ = fs::remove_file(sidecar(path, ".meta")).await;
+}
+
+/// A peer sharing files through an [crate::Indexer] and downloading them from other peers
+///
+/// Files this peer publishes get it as their origin, while downloaded replicas are polled for
+/// validity against their origin and removed once a newer version is published.
+#[derive(Clone)]
+pub struct PeerNode {
+    /// Connection to the indexer
+    client: IndexerClient,
+
+    /// Key this peer proves its identity with, whose [PeerId] is recorded as the origin of
+    /// published files, and how its connections to other peers are protected
+    endpoint: Endpoint,
+
+    /// Directories files are shared from and downloaded into
+    share: Arc<ShareRoot>,
+
+    /// Address other peers download from
+    dl_addr: SocketAddr,
+
+    /// TTL of queries
+    ttl: u8,
+
+    /// TTR given to published files
+    ttr: u8,
+
+    /// Replicas currently being polled for validity
+    polled: Arc<DashSet<PathBuf>>,
+
+    /// Merkle trees of shared files, kept as they are hashed so serving proofs never has to
+    trees: Arc<TreeCache>,
+
+    /// Called with everything worth reporting that happens outside of the result of a call
+    events: Arc<EventHook>,
+}
+
+impl PeerNode {
+    /// Create a [PeerNode] connecting to other peers through `endpoint`, sharing `share`
+    /// through `client` from `dl_addr`, querying the network with `ttl` and publishing files with
+    /// `ttr`
+    pub fn new(
+        client: &IndexerClient,
+        share: &Arc<ShareRoot>,
+        endpoint: &Endpoint,
+        dl_addr: SocketAddr,
+        ttl: u8,
+        ttr: u8,
+    ) -> Self {
+        PeerNode {
+            client: client.clone(),
+            endpoint: endpoint.clone(),
+            share: Arc::clone(share),
+            dl_addr,
+            ttl,
+            ttr,
+            polled: Arc::new(DashSet::new()),
+            trees: Arc::new(TreeCache::default()),
+            events: Arc::new(|_| {}),
+        }
+    }
+
+    /// Have `f` called with every [NodeEvent], such as replicas being removed or peers failing
+    /// during a download, instead of dropping them
+    pub fn on_event(mut self, f: impl Fn(&NodeEvent) + Send + Sync + 'static) -> Self {
+        self.events = Arc::new(f);
+        self
+    }
+
+    /// Pass `event` on to the hook set through [PeerNode::on_event]
+    fn report(&self, event: NodeEvent) {
+        (self.events)(&event);
+    }
+
+    /// Start a [PeerServer] for `share` on `dl_bind`, then [PeerNode::handshake] with the indexer
+    /// and [PeerNode::announce] the server, returning a [PeerNode] downloaded from at the bound
+    /// address
+    pub async fn listen(
+        client: &IndexerClient,
+        share: &Arc<ShareRoot>,
+        endpoint: &Endpoint,
+        dl_bind: SocketAddr,
+        ttl: u8,
+        ttr: u8,
+    ) -> Result<Self, NodeError> {
+        let listener = endpoint.listen(dl_bind).await?;
+        let node = Self::new(client, share, endpoint, listener.local_addr(), ttl, ttr);
+
+        let keypair = Arc::clone(node.endpoint.keypair());
+        let served = Arc::clone(share);
+        let trees = Arc::clone(&node.trees);
+        tokio::spawn(
+            listener
+                // Establish serve channel
+                .map(BaseChannel::with_defaults)
+                .map(move |channel| {
+                    let conn = channel.transport().get_ref();
+                    let server =
+                        PeerServer::new(conn.peer_addr(), conn.remote(), &keypair, &served, &trees);
+                    channel
+                        .execute(server.serve())
+                        .for_each(|response| async move {
+                            tokio::spawn(response);
+                        })
+                })
+                // Max 10 channels.
+                .buffer_unordered(10)
+                .for_each(|_| async {}),
+        );
+
+        node.handshake().await?;
+        node.announce().await?;
+        Ok(node)
+    }
+
+    /// ID this peer introduces itself with in [crate::Indexer::hello] and [crate::Peer::hello]
+    pub fn id(&self) -> PeerId {
+        self.endpoint.id()
+    }
+
+    /// Exchange [Hello]s with the indexer and prove this peer's ID, failing if it speaks an
+    /// incompatible protocol, and return the features both sides support
+    pub async fn handshake(&self) -> Result<Features, NodeError> {
+        crate::handshake(&self.client, self.endpoint.keypair(), Features::ALL).await
+    }
+
+    /// Directories files are shared from and downloaded into
+    pub fn share_root(&self) -> &Arc<ShareRoot> {
+        &self.share
+    }
+
+    /// Address other peers download from
+    pub fn dl_addr(&self) -> SocketAddr {
+        self.dl_addr
+    }
+
+    /// Tell the indexer which port other peers download from this peer on
+    pub async fn announce(&self) -> Result<(), NodeError> {
+        Ok(self
+            .client
+            .set_port(context::current(), self.dl_addr.port())
+            .await??)
+    }
+
+    /// Filename of the file at `path` inside the share directories, unless it is a sidecar or
+    /// hidden file
+    pub fn filename_of(&self, path: &Path) -> Option<String> {
+        self.share.dirs().iter().find_map(|dir| {
+            let filename = path
+                .strip_prefix(dir)
+                .ok()?
+                .to_str()?
+                .replace(std::path::MAIN_SEPARATOR, "/");
+            (!is_sidecar(path) && !self.share.is_hidden(path)).then_some(filename)
+        })
+    }
+
+    /// Filenames of every file inside the share directories, skipping the sidecar files
+    /// (`.meta`, `.part` and `.progress`) kept next to shared files
+    pub async fn files(&self) -> Vec<String> {
+        let mut filenames = Vec::new();
+        for dir in self.share.dirs() {
+            let mut pending = vec![dir.clone()];
+            while let Some(next) = pending.pop() {
+                let Ok(mut entries) = fs::read_dir(&next).await else {
+                    continue;
+                };
+                while let Ok(Some(entry)) = entries.next_entry().await {
+                    let path = entry.path();
+                    match entry.file_type().await {
+                        Ok(x) if x.is_dir() => pending.push(path),
+                        Ok(x) if x.is_file() => filenames.extend(self.filename_of(&path)),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        // earlier share directories shadow later ones
+        filenames.sort();
+        filenames.dedup();
+        filenames
+    }
+
+    /// (Try to) invalidate versions of `filename` older than `metadata` across the network
+    async fn invalidate_older(&self, metadata: &Metadata, filename: &str) {
+        let invalidation = Invalidation::new(self.endpoint.keypair(), filename, metadata.version);
+        match self
+            .client
+            .invalidate(context::current(), Uuid::new_v4(), invalidation)
+            .await
+        {
+            Ok(Ok(_)) => self.report(NodeEvent::Invalidated(filename.to_owned())),
+            Ok(Err(e)) => self.report(NodeEvent::InvalidateFailed(filename.to_owned(), e.into())),
+            Err(e) => self.report(NodeEvent::InvalidateFailed(filename.to_owned(), e.into())),
+        }
+    }
+
+    /// Poll the replica of `filename` at `path` for validity, unless it already is
+    fn poll(&self, filename: &str, path: &Path, metadata: Metadata) {
+        if !self.polled.insert(path.to_owned()) {
+            return;
+        }
+
+        let node = self.clone();
+        let (filename, path) = (filename.to_owned(), path.to_owned());
+        tokio::spawn(async move {
+            node.poll_file_validity(&filename, &path, metadata).await;
+            node.polled.remove(&path);
+        });
+    }
+
+    /// Address the origin of `filename` currently downloads from, found by querying the network
+    /// for the holder with its [PeerId]
+    async fn locate_origin(&self, filename: &str, origin: PeerId) -> Option<SocketAddr> {
+        self.client
+            .query(
+                context::current(),
+                Uuid::new_v4(),
+                Query::Exact(filename.to_owned()),
+                self.ttl,
+            )
+            .await
+            .ok()?
+            .into_iter()
+            .find(|hit| hit.holder == origin)
+            .map(|hit| hit.peer)
+    }
+
+    /// Make sure the origin of `filename` still offers the version described by `metadata`
+    async fn check_origin(&self, filename: &str, metadata: &Metadata) -> Result<(), NodeError> {
+        let origin = self
+            .locate_origin(filename, metadata.origin)
+            .await
+            .ok_or_else(|| NodeError::Transfer(format!("origin no longer offers {filename}")))?;
+        let (peer, _) = connect_peer(&self.endpoint, metadata.origin, &origin).await?;
+
+        // then, get the updated file metadata
+        let current = peer
+            .get_metadata(context::current(), filename.to_owned())
+            .await??;
+        if !current.verify(filename) {
+            return Err(NodeError::Transfer(format!(
+                "origin sent forged metadata for {filename}"
+            )));
+        }
+
+        // redownload needed
+        if current != *metadata {
+            return Err(NodeError::Outdated(filename.to_owned()));
+        }
+        Ok(())
+    }
+
+    /// Check that the replica of `filename` at `path` still matches its origin every `ttr`,
+    /// removing it once it doesn't
+    async fn poll_file_validity(&self, filename: &str, path: &Path, metadata: Metadata) {
+        loop {
+            // sleep for ttr, then poll
+            tokio::time::sleep(Duration::from_secs(metadata.ttr.into())).await;
+
+            self.report(NodeEvent::Polling(filename.to_owned()));
+            if let Err(e) = self.check_origin(filename, &metadata).await {
+                remove_replica(path).await;
+                self.report(NodeEvent::Removed(filename.to_owned(), e));
+                return;
+            }
+        }
+    }
+
+    /// Publish the current contents of `filename` inside the share directories as a new version
+    /// and register it with the indexer
+    pub async fn share(&self, filename: &str) -> Result<FileRecord, NodeError> {
+        // only files inside the share root can be served, so refuse anything else
+        let path = self
+            .share
+            .resolve(filename)
+            .ok_or_else(|| NodeError::NotShared(filename.to_owned()))?;
+
+        // record what the file looks like now so downloads can be verified
+        let hashed = hash_file(&path).await?;
+        let previous = Metadata::load(&path).await.ok();
+        let metadata = publish_metadata(
+            previous,
+            &hashed,
+            self.share.readers(&path).cloned(),
+            self.endpoint.keypair(),
+            filename,
+            self.ttr,
+        )?;
+        metadata.save(&path).await?;
+        self.trees.insert(path.clone(), Arc::new(hashed.tree));
+        self.invalidate_older(&metadata, filename).await;
+
+        let record = file_record(&self.share, filename, &path, &metadata).await?;
+        self.client
+            .register(context::current(), record.clone())
+            .await??;
+        Ok(record)
+    }
+
+    /// Bring the metadata of `filename` up to date and describe it for the indexer
+    ///
+    /// Files this peer is the origin of get a new version if they changed since they were last
+    /// registered. Replicas that no longer match their origin's version are not shared, and the
+    /// rest are polled for validity.
+    async fn refresh(&self, filename: &str) -> Result<FileRecord, NodeError> {
+        let path = self
+            .share
+            .resolve(filename)
+            .ok_or_else(|| NodeError::NotShared(filename.to_owned()))?;
+
+        let hashed = hash_file(&path).await?;
+        // the origin also publishes a new version when the readers it lets in change
+        let metadata = match Metadata::load(&path).await.ok() {
+            Some(x)
+                if x.verify(filename)
+                    && x.digest == hashed.digest
+                    && x.root == hashed.tree.root_hex()
+                    && (x.origin != self.id()
+                        || x.readers.as_ref() == self.share.readers(&path)) =>
+            {
+                x
+            }
+            Some(x) if x.origin != self.id() => {
+                return Err(NodeError::Outdated(filename.to_owned()))
+            }
+            previous => {
+                let metadata = publish_metadata(
+                    previous,
+                    &hashed,
+                    self.share.readers(&path).cloned(),
+                    self.endpoint.keypair(),
+                    filename,
+                    self.ttr,
+                )?;
+                metadata.save(&path).await?;
+                self.invalidate_older(&metadata, filename).await;
+                metadata
+            }
+        };
+
+        self.trees.insert(path.clone(), Arc::new(hashed.tree));
+
+        let record = file_record(&self.share, filename, &path, &metadata).await?;
+        if metadata.origin != self.id() {
+            self.poll(filename, &path, metadata);
+        }
+        Ok(record)
+    }
+
+    /// Register each of `filenames` with the indexer in a single call, only publishing new
+    /// versions of files that changed since they were last registered, and return which were
+    /// registered and why the rest were skipped
+    pub async fn sync(&self, filenames: &[String]) -> Result<Synced, NodeError> {
+        let mut synced = Synced::default();
+        for filename in filenames {
+            match self.refresh(filename).await {
+                Ok(x) => synced.registered.push(x),
+                Err(e) => synced.skipped.push((filename.clone(), e)),
+            }
+        }
+
+        if !synced.registered.is_empty() {
+            self.client
+                .register_many(context::current(), synced.registered.clone())
+                .await??;
+        }
+        Ok(synced)
+    }
+
+    /// [PeerNode::sync] every file inside the share directories
+    pub async fn sync_all(&self) -> Result<Synced, NodeError> {
+        self.sync(&self.files().await).await
+    }
+
+    /// Download `filename` into the share directories from a random peer (or from every peer at
+    /// once if `swarm` is set), returning where it was written to and its [Metadata]
+    ///
+    /// Interrupted downloads are resumed by the next peer, or by a later download of the same
+    /// version.
+    pub async fn download(
+        &self,
+        filename: &str,
+        swarm: bool,
+    ) -> Result<(PathBuf, Metadata), NodeError> {
+        let path = self
+            .share
+            .local_path(filename)
+            .ok_or_else(|| NodeError::InvalidName(filename.to_owned()))?;
+
+        let results = self
+            .client
+            .query(
+                context::current(),
+                Uuid::new_v4(),
+                Query::Exact(filename.to_owned()),
+                self.ttl,
+            )
+            .await?;
+
+        // try peers in random order, preferring those the indexer verified and resuming wherever
+        // the last one left off
+        let mut results = results;
+        results.shuffle(&mut rand::thread_rng());
+        results.sort_by_key(|hit| hit.verification != Verification::Verified);
+        let peers: Vec<_> = results
+            .into_iter()
+            .map(|hit| (hit.holder, hit.peer))
+            .collect();
+        if peers.is_empty() {
+            return Err(NodeError::NoPeers(filename.to_owned()));
+        }
+
+        let (metadata, tree) = if swarm {
+            download_swarm(&peers, &self.endpoint, filename, &path, &*self.events).await?
+        } else {
+            download_resumable(&peers, &self.endpoint, filename, &path, &*self.events).await?
+        };
+        metadata.save(&path).await?;
+        self.trees.insert(path.clone(), Arc::new(tree));
+        Ok((path, metadata))
+    }
+
+    /// [PeerNode::download] `filename` and register the replica with the indexer, polling it for
+    /// validity from then on
+    pub async fn fetch(&self, filename: &str, swarm: bool) -> Result<FileRecord, NodeError> {
+        let (path, metadata) = self.download(filename, swarm).await?;
+        let record = file_record(&self.share, filename, &path, &metadata).await?;
+        let registered = match self
+            .client
+            .register(context::current(), record.clone())
+            .await
+        {
+            Ok(x) => x.map_err(NodeError::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = registered {
+            remove_replica(&path).await;
+            return Err(e);
+        }
+
+        self.poll(filename, &path, metadata);
+        Ok(record)
+    }
+
+    /// Search the whole network (or only the indexer if `local` is set) for files matching
+    /// `query`
+    pub async fn search(&self, query: &Query, local: bool) -> Result<Vec<Hit>, NodeError> {
+        let hits = if local {
+            self.client
+                .search(context::current(), query.clone())
+                .await?
+        } else {
+            self.client
+                .query(context::current(), Uuid::new_v4(), query.clone(), self.ttl)
+                .await?
+        };
+        Ok(hits)
+    }
+
+    /// Deregister `filename` with the indexer
+    pub async fn unshare(&self, filename: &str) -> Result<(), NodeError> {
+        Ok(self
+            .client
+            .deregister(context::current(), filename.to_owned())
+            .await??)
+    }
+
+    /// Deregister every one of `filenames` with the indexer in a single call
+    pub async fn unshare_many(&self, filenames: Vec<String>) -> Result<(), NodeError> {
+        Ok(self
+            .client
+            .deregister_many(context::current(), filenames)
+            .await??)
+    }
+
+    /// Renew the lease on the indexer every third of its length, announcing this peer and
+    /// registering either `only` that file or everything inside the share directories again if
+    /// it lapsed
+    ///
+    /// Files are registered again through [PeerNode::sync], so a lapsed lease never publishes a
+    /// new version. Only files whose contents or readers changed get one.
+    pub async fn keep_alive(self, only: Option<String>) {
+        let mut every = Duration::from_secs(1);
+        loop {
+            tokio::time::sleep(every).await;
+            match self.client.heartbeat(context::current()).await {
+                Ok(Ok(lease)) => every = Duration::from_secs((lease / 3).max(1)),
+                Ok(Err(NekoError::LeaseLapsed)) => {
+                    self.report(NodeEvent::LeaseLapsed);
+                    if let Err(e) = self.announce().await {
+                        self.report(NodeEvent::ReregisterFailed(e));
+                        continue;
+                    }
+                    let synced = match &only {
+                        Some(x) => self.sync(slice::from_ref(x)).await,
+                        None => self.sync_all().await,
+                    };
+                    match synced {
+                        Ok(x) => {
+                            for (filename, e) in x.skipped {
+                                self.report(NodeEvent::NotShared(filename, e));
+                            }
+                        }
+                        Err(e) => self.report(NodeEvent::ReregisterFailed(e)),
+                    }
+                }
+                Ok(Err(e)) => self.report(NodeEvent::HeartbeatFailed(e.into())),
+                Err(e) => self.report(NodeEvent::HeartbeatFailed(e.into())),
+            }
+        }
+    }
+
+    /// Remove all of this peer's registrations from the indexer and give up its lease
+    pub async fn disconnect(&self) -> Result<(), NodeError> {
+        Ok(self.client.disconnect_peer(context::current()).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tarpc::client;
+
+    use super::*;
+    use crate::{IndexerNode, Security};
+
+    #[tokio::test]
+    async fn lapsed_lease_keeps_version() {
+        let indexer = IndexerNode::builder().start().await.unwrap();
+        let dir = std::env::temp_dir().join(format!("nekop2p-lease-{0}", std::process::id()));
+        fs::create_dir_all(&dir).await.unwrap();
+        fs::write(dir.join("seeded.txt"), "unchanged")
+            .await
+            .unwrap();
+
+        let keypair = Arc::new(Keypair::generate());
+        let endpoint = Endpoint::new(&keypair, Security::Plaintext);
+        let transport = endpoint
+            .connect(indexer.local_addr(), Some(indexer.id()))
+            .await
+            .unwrap();
+        let client = IndexerClient::new(client::Config::default(), transport).spawn();
+        let share = Arc::new(ShareRoot::new([&dir]).unwrap());
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let seen = Arc::clone(&events);
+        let node = PeerNode::listen(
+            &client,
+            &share,
+            &endpoint,
+            "127.0.0.1:0".parse().unwrap(),
+            0,
+            60,
+        )
+        .await
+        .unwrap()
+        .on_event(move |e| seen.lock().unwrap().push(e.to_string()));
+
+        let record = node.share("seeded.txt").await.unwrap();
+        assert_eq!(record.version, 0);
+
+        // giving up the lease makes the next heartbeat find it lapsed
+        node.disconnect().await.unwrap();
+        events.lock().unwrap().clear();
+        let heartbeat = tokio::spawn(node.clone().keep_alive(Some("seeded.txt".to_owned())));
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        heartbeat.abort();
+
+        let events = events.lock().unwrap().clone();
+        assert!(
+            events.contains(&NodeEvent::LeaseLapsed.to_string()),
+            "{events:?}"
+        );
+        assert!(
+            !events.iter().any(|x| x.starts_with("Sent invalidation")),
+            "{events:?}"
+        );
+        let metadata = Metadata::load(&dir.join("seeded.txt")).await.unwrap();
+        assert_eq!(metadata.version, 0);
+        let hits = indexer
+            .store()
+            .search(&Query::Exact("seeded.txt".to_owned()));
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].record.version, 0);
+
+        indexer.shutdown();
+        fs::remove_dir_all(&dir).await.unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! On-disk state for partially downloaded files
+//!
+//! A download of `foo.bin` is written to `foo.bin.part`, with the byte ranges received so far
+//! recorded in `foo.bin.progress` alongside the [Metadata] of the version being fetched.
+use std::{
+    io::{self, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs,
+    io::{AsyncSeekExt, AsyncWriteExt},
+};
+
+use crate::{hash_file, sidecar, MerkleTree, Metadata, CHUNK_SIZE};
+
+/// Progress record stored next to the `.part` file
+#[derive(Deserialize, Serialize)]
+struct Record {
+    /// Total size of the file in bytes
+    size: u64,
+
+    /// Sorted, non-overlapping byte ranges `[start, end)` already written to the `.part` file
+    done: Vec<(u64, u64)>,
+
+    /// Metadata of the version being downloaded
+    metadata: Metadata,
+}
+
+/// Handle to a partial download and its progress record
+pub struct Partial {
+    /// Where the file is being downloaded to
+    path: PathBuf,
+
+    /// Progress so far
+    record: Record,
+
+    /// The `.part` file being written to
+    file: fs::File,
+}
+
+/// Path of the partial data for `path`
+fn part_path(path: &Path) -> PathBuf {
+    sidecar(path, ".part")
+}
+
+/// Path of the progress record for `path`
+fn progress_path(path: &Path) -> PathBuf {
+    sidecar(path, ".progress")
+}
+
+impl Partial {
+    /// Resume the partial download to `path` if one exists for the same `metadata` and `size`,
+    /// otherwise throw out any stale partial data and start fresh
+    pub async fn resume(path: &Path, metadata: &Metadata, size: u64) -> io::Result<Self> {
+        let mut record = None;
+        if let Ok(text) = fs::read_to_string(progress_path(path)).await {
+            match toml::from_str::<Record>(&text) {
+                Ok(x) if x.metadata == *metadata && x.size == size => record = Some(x),
+                _ => Self::discard(path).await,
+            }
+        }
+
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(part_path(path))
+            .await?;
+        file.set_len(size).await?;
+
+        let partial = Partial {
+            path: path.to_owned(),
+            record: record.unwrap_or_else(|| Record {
+                size,
+                done: Vec::new(),
+                metadata: metadata.clone(),
+            }),
+            file,
+        };
+        partial.save().await?;
+        Ok(partial)
+    }
+
+    /// Persist the progress record
+    async fn save(&self) -> io::Result<()> {
+        let text = toml::to_string(&self.record).map_err(io::Error::other)?;
+        fs::write(progress_path(&self.path), text).await
+    }
+
+    /// Number of bytes already received
+    pub fn received(&self) -> u64 {
+        self.record.done.iter().map(|(s, e)| e - s).sum()
+    }
+
+    /// Byte ranges `[start, end)` still missing, split into pieces of at most [CHUNK_SIZE]
+    pub fn pieces(&self) -> Vec<(u64, u64)> {
+        let mut gaps = Vec::new();
+        let mut cursor = 0;
+        for &(start, end) in self.record.done.iter() {
+            if start > cursor {
+                gaps.push((cursor, start));
+            }
+            cursor = end;
+        }
+        if cursor < self.record.size {
+            gaps.push((cursor, self.record.size));
+        }
+
+        gaps.into_iter()
+            .flat_map(|(start, end)| {
+                (start..end)
+                    .step_by(CHUNK_SIZE as usize)
+                    .map(move |s| (s, end.min(s + u64::from(CHUNK_SIZE))))
+            })
+            .collect()
+    }
+
+    /// Write `chunk` at `offset` and record it as received
+    pub async fn write(&mut self, offset: u64, chunk: &[u8]) -> io::Result<()> {
+        self.file.seek(SeekFrom::Start(offset)).await?;
+        self.file.write_all(chunk).await?;
+        self.file.flush().await?;
+
+        self.mark(offset, offset + chunk.len() as u64);
+        self.save().await
+    }
+
+    /// Record `[start, end)` as written, merging it with any adjacent ranges
+    fn mark(&mut self, start: u64, end: u64) {
+        let done = &mut self.record.done;
+        done.push((start, end));
+        done.sort_unstable();
+
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(done.len());
+        for &(s, e) in done.iter() {
+            match merged.last_mut() {
+                Some(last) if s <= last.1 => last.1 = last.1.max(e),
+                _ => merged.push((s, e)),
+            }
+        }
+        *done = merged;
+    }
+
+    /// Check the completed `.part` file against the digests recorded by the origin, returning its
+    /// Merkle tree if it matches
+    pub async fn verify(&self) -> io::Result<Option<MerkleTree>> {
+        let hashed = hash_file(part_path(&self.path)).await?;
+        let metadata = &self.record.metadata;
+        Ok((hashed.size == metadata.size
+            && hashed.digest == metadata.digest
+            && hashed.tree.root_hex() == metadata.root)
+            .then_some(hashed.tree))
+    }
+
+    /// Move the completed `.part` file into place and drop the progress record
+    pub async fn finish(self) -> io::Result<()> {
+        drop(self.file);
+        fs::rename(part_path(&self.path), &self.path).await?;
+        let _ = fs::remove_file(progress_path(&self.path)).await;
+        Ok(())
+    }
+
+    /// Remove any partial download state of `path`
+    pub async fn discard(path: &Path) {
+        let _ = fs::remove_file(part_path(path)).await;
+        let _ = fs::remove_file(progress_path(path)).await;
+    }
+}
@@ -1,22 +1,208 @@
-use std::net::SocketAddr;
+use std::{
+    collections::BTreeSet,
+    io::{self, ErrorKind, SeekFrom},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use dashmap::DashMap;
 
 use serde::{Deserialize, Serialize};
 use tarpc::context::Context;
-use tokio::fs;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+use crate::{
+    hash_file,
+    hello::Session,
+    identity::{identify_message, signature_hex},
+    share::sidecar,
+    Features, FileDigest, FileRecord, Hash, Hello, Keypair, MerkleTree, NekoError, Peer, PeerId,
+    ShareRoot, Signature, CHUNK_SIZE,
+};
 
-use crate::Peer;
+/// Merkle trees of served files, shared between all connections of a [PeerServer]
+pub type TreeCache = DashMap<PathBuf, Arc<MerkleTree>>;
+
+/// Bytes of `fields` joined together, each prefixed with its length so no two lists of fields
+/// join into the same bytes
+fn signed_bytes(domain: &[u8], fields: &[&[u8]]) -> Vec<u8> {
+    let mut bytes = domain.to_vec();
+    for field in fields {
+        bytes.extend_from_slice(&(field.len() as u64).to_be_bytes());
+        bytes.extend_from_slice(field);
+    }
+    bytes
+}
 
 /// [Peer] downloaded file metadata
-#[derive(Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
 pub struct Metadata {
-    /// Server the file originated from (not necessarily downloaded)
-    pub origin_server: SocketAddr,
+    /// Peer the file originated from (not necessarily downloaded from), whose [PeerId] is the
+    /// public key [Metadata::signature] is checked against
+    pub origin: PeerId,
 
     /// Version number of the file
     pub version: u8,
 
     /// TTR of the file, or when to check for validity
     pub ttr: u8,
+
+    /// Size of the file in bytes, as recorded by the origin
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 digest of the file, as recorded by the origin
+    pub digest: String,
+
+    /// Hex-encoded Merkle root over the file's [CHUNK_SIZE] pieces, as recorded by the origin
+    pub root: String,
+
+    /// Only peers the origin lets download the file, or `None` if anyone may
+    #[serde(default)]
+    pub readers: Option<BTreeSet<PeerId>>,
+
+    /// Origin's signature over the rest of the metadata and the name of the file
+    #[serde(with = "signature_hex")]
+    pub signature: Signature,
+}
+
+impl Metadata {
+    /// Metadata of version `version` of `filename`, which now hashes to `hashed` and may only be
+    /// downloaded by `readers`, published and signed by its origin `keypair`
+    pub fn new(
+        keypair: &Keypair,
+        filename: &str,
+        version: u8,
+        ttr: u8,
+        hashed: &FileDigest,
+        readers: Option<BTreeSet<PeerId>>,
+    ) -> Self {
+        let mut metadata = Metadata {
+            origin: keypair.id(),
+            version,
+            ttr,
+            size: hashed.size,
+            digest: hashed.digest.clone(),
+            root: hashed.tree.root_hex(),
+            readers,
+            signature: Signature::from_bytes(&[0; 64]),
+        };
+        metadata.signature = keypair.sign(&metadata.signed(filename));
+        metadata
+    }
+
+    /// What the origin signs to publish this metadata for `filename`
+    fn signed(&self, filename: &str) -> Vec<u8> {
+        // an empty list of readers is not the same as letting anyone read
+        let readers: Vec<u8> = match &self.readers {
+            Some(x) => [1]
+                .into_iter()
+                .chain(x.iter().flat_map(|x| *x.as_bytes()))
+                .collect(),
+            None => vec![0],
+        };
+        signed_bytes(
+            b"nekop2p metadata",
+            &[
+                self.origin.as_bytes(),
+                &[self.version, self.ttr],
+                &self.size.to_be_bytes(),
+                self.digest.as_bytes(),
+                self.root.as_bytes(),
+                &readers,
+                filename.as_bytes(),
+            ],
+        )
+    }
+
+    /// Check that the metadata of `filename` was signed by its origin
+    pub fn verify(&self, filename: &str) -> bool {
+        self.origin.verify(&self.signed(filename), &self.signature)
+    }
+
+    /// Peers allowed to download the copy of the file at `path` inside `share`, which both the
+    /// origin and the rules of `share` have to let in, or `None` if anyone may
+    pub fn readers_in(&self, share: &ShareRoot, path: &Path) -> Option<BTreeSet<PeerId>> {
+        match (&self.readers, share.readers(path)) {
+            (Some(x), Some(y)) => Some(x.intersection(y).copied().collect()),
+            (Some(x), None) | (None, Some(x)) => Some(x.clone()),
+            (None, None) => None,
+        }
+    }
+
+    /// Read the metadata kept in the `.meta` file next to `path`
+    pub async fn load(path: &Path) -> io::Result<Self> {
+        let metadata_text = fs::read_to_string(sidecar(path, ".meta")).await?;
+        toml::from_str(metadata_text.as_str())
+            .map_err(|e| io::Error::new(ErrorKind::InvalidData, e))
+    }
+
+    /// Write the metadata to the `.meta` file next to `path`
+    pub async fn save(&self, path: &Path) -> io::Result<()> {
+        let metadata_text = toml::to_string_pretty(self).map_err(io::Error::other)?;
+        fs::write(sidecar(path, ".meta"), metadata_text).await
+    }
+}
+
+/// Notice from the origin of a file that its replicas older than a new version are stale,
+/// spread through [crate::Indexer::invalidate] and [Peer::invalidate]
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Invalidation {
+    /// Peer the file originated from, which signed the invalidation
+    pub origin: PeerId,
+
+    /// Name of the invalidated file
+    pub filename: String,
+
+    /// Version replacing the stale replicas
+    pub version: u8,
+
+    /// Origin's signature over the rest of the invalidation
+    pub signature: Signature,
+}
+
+impl Invalidation {
+    /// Invalidate replicas of `filename` older than `version`, signed by its origin `keypair`
+    pub fn new(keypair: &Keypair, filename: &str, version: u8) -> Self {
+        let mut invalidation = Invalidation {
+            origin: keypair.id(),
+            filename: filename.to_owned(),
+            version,
+            signature: Signature::from_bytes(&[0; 64]),
+        };
+        invalidation.signature = keypair.sign(&invalidation.signed());
+        invalidation
+    }
+
+    /// What the origin signs to send this invalidation
+    fn signed(&self) -> Vec<u8> {
+        signed_bytes(
+            b"nekop2p invalidate",
+            &[
+                self.origin.as_bytes(),
+                &[self.version],
+                self.filename.as_bytes(),
+            ],
+        )
+    }
+
+    /// Check that the invalidation was signed by the origin it names
+    pub fn verify(&self) -> bool {
+        self.origin.verify(&self.signed(), &self.signature)
+    }
+
+    /// Whether the copy `holder` registered as `record` is a replica of the invalidated file
+    /// older than the new version, leaving the origin's own copy and files of other origins that
+    /// happen to share the name alone
+    pub fn supersedes(&self, holder: PeerId, record: &FileRecord) -> bool {
+        holder != self.origin
+            && record.filename == self.filename
+            && record.origin == self.origin
+            && record.version < self.version
+    }
 }
 
 /// Reference [Peer] implementation
@@ -24,68 +210,254 @@ pub struct Metadata {
 pub struct PeerServer {
     /// Address of remote peer
     addr: SocketAddr,
+
+    /// Key behind the ID this peer answers [Peer::hello] with, which answers [Peer::identify]
+    keypair: Arc<Keypair>,
+
+    /// State of the connection, shared between its requests
+    session: Arc<Mutex<Session>>,
+
+    /// Directories files are served from
+    share: Arc<ShareRoot>,
+
+    /// Merkle trees of files that proofs were requested for
+    trees: Arc<TreeCache>,
 }
 
 impl PeerServer {
-    /// Create a new [PeerServer] with the address of the remote peer
-    pub fn new(addr: SocketAddr) -> Self {
-        PeerServer { addr }
+    /// Create a new [PeerServer] with the address of the remote peer, which proved to be `remote`
+    /// if the connection is encrypted, introducing itself with `keypair`, serving only files
+    /// inside `share` and keeping Merkle trees in the shared `trees`
+    pub fn new(
+        addr: SocketAddr,
+        remote: Option<PeerId>,
+        keypair: &Arc<Keypair>,
+        share: &Arc<ShareRoot>,
+        trees: &Arc<TreeCache>,
+    ) -> Self {
+        PeerServer {
+            addr,
+            keypair: Arc::clone(keypair),
+            session: Arc::new(Mutex::new(Session::new(remote))),
+            share: Arc::clone(share),
+            trees: Arc::clone(trees),
+        }
+    }
+
+    /// Get the Merkle tree of `path`, rehashing the file if the cached tree doesn't match the
+    /// root in its `metadata`
+    ///
+    /// Trees are normally cached when files are published, shared or downloaded. Rehashing runs
+    /// in a task of its own, so the tree still gets cached for the next request if this one runs
+    /// past its deadline.
+    async fn tree(&self, path: &Path, metadata: &Metadata) -> io::Result<Arc<MerkleTree>> {
+        if let Some(tree) = self.trees.get(path) {
+            if tree.root_hex() == metadata.root {
+                return Ok(Arc::clone(&tree));
+            }
+        }
+
+        let (path, trees) = (path.to_owned(), Arc::clone(&self.trees));
+        tokio::spawn(async move {
+            let tree = Arc::new(hash_file(&path).await?.tree);
+            trees.insert(path, Arc::clone(&tree));
+            Ok(tree)
+        })
+        .await?
+    }
+
+    /// Path of a shared `filename`
+    fn resolve(&self, filename: &str) -> Result<PathBuf, NekoError> {
+        self.share
+            .resolve(filename)
+            .ok_or_else(|| NekoError::NotFound(filename.to_owned()))
+    }
+
+    /// Path of a shared `filename`, as long as the remote peer may read it
+    async fn readable(&self, filename: &str) -> Result<PathBuf, NekoError> {
+        let path = self.resolve(filename)?;
+        let readers = match Metadata::load(&path).await {
+            Ok(x) => x.readers_in(&self.share, &path),
+            Err(_) => self.share.readers(&path).cloned(),
+        };
+        let peer = self.session.lock().unwrap().peer();
+        if readers.is_some_and(|x| !peer.is_some_and(|peer| x.contains(&peer))) {
+            match peer {
+                Some(x) => println!("Refused {filename} to {x} at {0}", self.addr),
+                None => println!("Refused {filename} to unidentified {0}", self.addr),
+            }
+            return Err(NekoError::PermissionDenied(filename.to_owned()));
+        }
+        Ok(path)
+    }
+
+    /// Read the metadata of a shared `filename`
+    async fn read_metadata(&self, filename: &str) -> Result<Metadata, NekoError> {
+        let path = self.resolve(filename)?;
+        Metadata::load(&path).await.map_err(|e| match e.kind() {
+            ErrorKind::NotFound => NekoError::NoMetadata(filename.to_owned()),
+            _ => NekoError::io(filename, e),
+        })
     }
 }
 
 impl Peer for PeerServer {
-    async fn download_file(self, _: Context, filename: String) -> Option<Vec<u8>> {
+    async fn hello(self, _: Context, hello: Hello) -> Result<Hello, NekoError> {
+        let ours = Hello::new(self.keypair.id(), Features::ALL);
+        match ours.negotiate(&hello) {
+            Ok(x) => println!(
+                "Hello from node {0} at {1} (features: {x})",
+                hello.node_id, self.addr
+            ),
+            Err(e) => {
+                println!("Refused node {0} at {1}: {e}", hello.node_id, self.addr);
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.session.lock().unwrap().greet(hello, ours) {
+            println!(
+                "Refused node {0} at {1}: connection belongs to another node",
+                hello.node_id, self.addr
+            );
+            return Err(e);
+        }
+        Ok(ours)
+    }
+
+    async fn identify(self, _: Context, signature: Signature) -> Result<Signature, NekoError> {
+        let identified = self.session.lock().unwrap().identify(&signature);
+        match identified {
+            Ok((theirs, _)) => {
+                println!("Identified {0} at {1}", theirs.node_id, self.addr);
+                Ok(self
+                    .keypair
+                    .sign(&identify_message(&theirs.node_id, &theirs.challenge)))
+            }
+            Err(e) => {
+                println!("Refused identification from {0}: {e}", self.addr);
+                Err(e)
+            }
+        }
+    }
+
+    async fn file_size(self, _: Context, filename: String) -> Result<u64, NekoError> {
         println!(
             "Handling download request for {0} from {1}",
             filename, self.addr
         );
-        fs::read(filename).await.ok()
+        let path = self.readable(&filename).await?;
+        fs::metadata(path)
+            .await
+            .map(|m| m.len())
+            .map_err(|e| NekoError::io(&filename, e))
+    }
+
+    async fn read_chunk(
+        self,
+        _: Context,
+        filename: String,
+        offset: u64,
+        len: u32,
+        compress: bool,
+    ) -> Result<Vec<u8>, NekoError> {
+        let path = self.readable(&filename).await?;
+        let read = async {
+            let mut file = fs::File::open(path).await?;
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            // never send more than a single chunk at once
+            let mut chunk = Vec::new();
+            file.take(len.min(CHUNK_SIZE).into())
+                .read_to_end(&mut chunk)
+                .await?;
+            Ok(chunk)
+        };
+        let chunk = read.await.map_err(|e| NekoError::io(&filename, e))?;
+        if compress {
+            return Ok(lz4_flex::compress_prepend_size(&chunk));
+        }
+        Ok(chunk)
     }
 
     async fn invalidate(
         self,
         _: Context,
         _: uuid::Uuid,
-        origin_server: SocketAddr,
-        filename: String,
-    ) {
+        invalidation: Invalidation,
+    ) -> Result<(), NekoError> {
+        let Invalidation {
+            origin,
+            ref filename,
+            version,
+            ..
+        } = invalidation;
+
+        // only the origin can invalidate its files
+        if !invalidation.verify() {
+            println!(
+                "Recieved forged invalidation message for {0}::{1} from {2}",
+                filename, origin, self.addr
+            );
+            return Err(NekoError::BadSignature);
+        }
+
         // get origin server and version from metadata
-        let metadata_text = match fs::read_to_string(filename.clone() + ".meta").await {
-            Ok(x) => x,
-            Err(_) => return,
-        };
-        let metadata: Metadata = match toml::from_str(metadata_text.as_str()) {
-            Ok(x) => x,
-            Err(_) => return,
-        };
+        let path = self.resolve(filename)?;
+        let metadata = self.read_metadata(filename).await?;
 
-        // remove if origin server matches
-        if origin_server == metadata.origin_server {
-            // got an invalidation message of a file, assume file is bad and delete
+        // remove if origin server matches and the replica is older
+        if origin != metadata.origin {
             println!(
-                "Recieved invalidation message for {0}::{1} from {2}",
-                filename, origin_server, self.addr
+                "Recieved invalid invalidation message for {0} from {2} with bad origin {1}",
+                filename, origin, self.addr
             );
-            let _ = fs::remove_file(filename.clone()).await;
-            let _ = fs::remove_file(filename + ".meta").await;
+            Err(NekoError::InvalidationRejected(format!(
+                "{filename} originates from {0}, not {origin}",
+                metadata.origin
+            )))
+        } else if metadata.version >= version {
+            println!(
+                "Recieved stale invalidation message for {0}::{1} version {2} from {3}",
+                filename, origin, version, self.addr
+            );
+            Err(NekoError::InvalidationRejected(format!(
+                "{filename} is already at version {0}",
+                metadata.version
+            )))
         } else {
+            // got an invalidation message of a file, assume file is bad and delete
             println!(
-                "Recieved invalid invalidation message for {0} from {2} with bad origin {1}",
-                filename, origin_server, self.addr
+                "Recieved invalidation message for {0}::{1} from {2}",
+                filename, origin, self.addr
             );
+            let _ = fs::remove_file(sidecar(&path, ".meta")).await;
+            let _ = fs::remove_file(path).await;
+            Ok(())
         }
     }
 
-    async fn get_metadata(self, _: Context, filename: String) -> Option<Metadata> {
+    async fn get_proof(
+        self,
+        _: Context,
+        filename: String,
+        piece: u64,
+    ) -> Result<Vec<Hash>, NekoError> {
+        let path = self.readable(&filename).await?;
+        let metadata = self.read_metadata(&filename).await?;
+        self.tree(&path, &metadata)
+            .await
+            .map_err(|e| NekoError::io(&filename, e))?
+            .proof(piece)
+            .ok_or(NekoError::NoSuchPiece(piece))
+    }
+
+    async fn get_metadata(self, _: Context, filename: String) -> Result<Metadata, NekoError> {
         println!(
             "Handling metadata request for {0} from {1}",
             filename, self.addr
         );
-        // get origin server and version from metadata
-        let metadata_text = match fs::read_to_string(filename.clone() + ".meta").await {
-            Ok(x) => x,
-            Err(_) => return None,
-        };
-        toml::from_str(metadata_text.as_str()).ok()
+        self.readable(&filename).await?;
+        self.read_metadata(&filename).await
     }
 }
@@ -0,0 +1,540 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    error::Error,
+    fmt,
+    net::SocketAddr,
+    ops::Bound,
+    str::FromStr,
+    sync::RwLock,
+};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::{FileRecord, PeerId, Verification};
+
+/// Filename query understood by [crate::Indexer::search] and [crate::Indexer::query]
+///
+/// Parsed from text with an optional `exact:`, `prefix:`, `sub:`, `glob:` or `fuzzy:` prefix.
+/// Text without a prefix is a glob if it contains `*`, `?` or `[`, and an exact name otherwise.
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub enum Query {
+    /// Filename equal to the given name
+    Exact(String),
+
+    /// Filenames starting with the given text
+    Prefix(String),
+
+    /// Filenames containing the given text
+    Substring(String),
+
+    /// Filenames matching a shell-style pattern using `*`, `?` and `[...]`
+    Glob(String),
+
+    /// Filenames within [Query::max_edits] edits of the given name
+    Fuzzy(String),
+}
+
+/// A file matching a [Query] as registered by one of its holders
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct Hit {
+    /// Matched file, as described by `holder`
+    pub record: FileRecord,
+
+    /// Peer holding the file
+    pub holder: PeerId,
+
+    /// Address to download the file from
+    pub peer: SocketAddr,
+
+    /// Whether the holder proved to the indexer that it has the file
+    pub verification: Verification,
+}
+
+/// Lay out each [Hit] as a row of a table describing the file and the peer holding it
+pub fn format_hits(hits: &[Hit]) -> String {
+    let header = [
+        "FILENAME", "SIZE", "VERSION", "ORIGIN", "MODIFIED", "DIGEST", "PEER", "STATUS",
+    ];
+    let rows: Vec<[String; 8]> = hits
+        .iter()
+        .map(|h| {
+            [
+                h.record.filename.clone(),
+                h.record.size.to_string(),
+                h.record.version.to_string(),
+                h.record.origin.to_string().chars().take(16).collect(),
+                humantime::format_rfc3339_seconds(h.record.modified).to_string(),
+                h.record.digest.chars().take(16).collect(),
+                h.peer.to_string(),
+                h.verification.to_string(),
+            ]
+        })
+        .collect();
+
+    // pad every column to its widest cell
+    let mut widths = header.map(str::len);
+    for row in rows.iter() {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let format_row = |row: &[&str]| {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:<width$}"))
+            .collect();
+        format!("{0}\n", cells.join("  ").trim_end())
+    };
+    let mut table = format_row(&header);
+    for row in rows.iter() {
+        table += &format_row(&row.each_ref().map(String::as_str));
+    }
+    table
+}
+
+/// Error returned when parsing a [Query] with an invalid glob pattern
+#[derive(Debug)]
+pub struct ParseQueryError(String);
+
+impl fmt::Display for ParseQueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "invalid pattern: {0}", self.0)
+    }
+}
+
+impl Error for ParseQueryError {}
+
+impl FromStr for Query {
+    type Err = ParseQueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let query = match s.split_once(':') {
+            Some(("exact", x)) => Query::Exact(x.to_owned()),
+            Some(("prefix", x)) => Query::Prefix(x.to_owned()),
+            Some(("sub", x)) => Query::Substring(x.to_owned()),
+            Some(("glob", x)) => Query::Glob(x.to_owned()),
+            Some(("fuzzy", x)) => Query::Fuzzy(x.to_owned()),
+            _ if s.contains(['*', '?', '[']) => Query::Glob(s.to_owned()),
+            _ => Query::Exact(s.to_owned()),
+        };
+
+        if let Query::Glob(x) = &query {
+            Pattern::new(x).map_err(|e| ParseQueryError(e.msg.to_owned()))?;
+        }
+        Ok(query)
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Query::Exact(x) => write!(f, "{x}"),
+            Query::Prefix(x) => write!(f, "prefix:{x}"),
+            Query::Substring(x) => write!(f, "sub:{x}"),
+            Query::Glob(x) => write!(f, "glob:{x}"),
+            Query::Fuzzy(x) => write!(f, "fuzzy:{x}"),
+        }
+    }
+}
+
+/// [Query] prepared for matching many filenames
+enum Matcher<'a> {
+    Exact(&'a str),
+    Prefix(&'a str),
+    Substring(&'a str),
+    Glob(Pattern),
+    Fuzzy(&'a str, usize),
+}
+
+impl Matcher<'_> {
+    /// Whether `filename` matches
+    fn matches(&self, filename: &str) -> bool {
+        match self {
+            Matcher::Exact(x) => filename == *x,
+            Matcher::Prefix(x) => filename.starts_with(x),
+            Matcher::Substring(x) => filename.contains(x),
+            Matcher::Glob(x) => x.matches(filename),
+            Matcher::Fuzzy(x, edits) => strsim::levenshtein(x, filename) <= *edits,
+        }
+    }
+}
+
+impl Query {
+    /// Edits (insertions, deletions or substitutions) a [Query::Fuzzy] match may be away from
+    /// `name`, which is one per four characters and at least one
+    pub fn max_edits(name: &str) -> usize {
+        (name.chars().count() / 4).max(1)
+    }
+
+    /// Prepare the query for matching, which fails only for an invalid glob
+    fn matcher(&self) -> Option<Matcher<'_>> {
+        Some(match self {
+            Query::Exact(x) => Matcher::Exact(x),
+            Query::Prefix(x) => Matcher::Prefix(x),
+            Query::Substring(x) => Matcher::Substring(x),
+            Query::Glob(x) => Matcher::Glob(Pattern::new(x).ok()?),
+            Query::Fuzzy(x) => Matcher::Fuzzy(x, Query::max_edits(x)),
+        })
+    }
+
+    /// Whether `filename` matches the query
+    pub fn matches(&self, filename: &str) -> bool {
+        self.matcher().is_some_and(|m| m.matches(filename))
+    }
+}
+
+/// Three consecutive characters of a filename
+type Trigram = [char; 3];
+
+/// Character used to pad both ends of a filename, so anchored text gets trigrams of its own
+const PAD: char = '\0';
+
+/// Distinct trigrams of `text`, padded at the start and/or end with [PAD]
+fn trigrams(text: &str, pad_start: bool, pad_end: bool) -> HashSet<Trigram> {
+    let mut chars = Vec::new();
+    if pad_start {
+        chars.extend([PAD, PAD]);
+    }
+    chars.extend(text.chars());
+    if pad_end {
+        chars.extend([PAD, PAD]);
+    }
+    chars.windows(3).map(|w| [w[0], w[1], w[2]]).collect()
+}
+
+/// Literal runs of a glob `pattern`, each flagged with whether it is anchored to the start and
+/// to the end of the filename
+fn glob_literals(pattern: &str) -> Vec<(String, bool, bool)> {
+    let mut literals = Vec::new();
+    let mut current = String::new();
+    let mut anchored = true;
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' | '?' | '[' => {
+                if !current.is_empty() {
+                    literals.push((std::mem::take(&mut current), anchored, false));
+                }
+                anchored = false;
+
+                // skip over the bracket expression, where a leading `]` is a member
+                if c == '[' {
+                    chars.next_if_eq(&'!');
+                    chars.next_if_eq(&']');
+                    for c in chars.by_ref() {
+                        if c == ']' {
+                            break;
+                        }
+                    }
+                }
+            }
+            _ => current.push(c),
+        }
+    }
+    literals.push((current, anchored, true));
+    literals
+}
+
+/// Sorted filenames and their trigrams
+#[derive(Default)]
+struct Names {
+    /// Every indexed filename in order, for prefix ranges
+    sorted: BTreeSet<String>,
+
+    /// Filenames containing each (padded) trigram
+    trigrams: HashMap<Trigram, HashSet<String>>,
+}
+
+impl Names {
+    /// Filenames starting with `prefix`
+    fn with_prefix(&self, prefix: &str) -> HashSet<&String> {
+        self.sorted
+            .range::<str, _>((Bound::Included(prefix), Bound::Unbounded))
+            .take_while(|x| x.starts_with(prefix))
+            .collect()
+    }
+
+    /// Filenames containing every one of `grams`
+    fn with_all(&self, grams: &HashSet<Trigram>) -> HashSet<&String> {
+        let mut postings = Vec::new();
+        for gram in grams {
+            match self.trigrams.get(gram) {
+                Some(x) => postings.push(x),
+                None => return HashSet::new(),
+            }
+        }
+
+        // walk the rarest trigram's names and check them against the rest
+        postings.sort_by_key(|x| x.len());
+        match postings.split_first() {
+            Some((first, rest)) => first
+                .iter()
+                .filter(|x| rest.iter().all(|p| p.contains(*x)))
+                .collect(),
+            None => self.sorted.iter().collect(),
+        }
+    }
+
+    /// Filenames sharing at least `min` of `grams`
+    fn with_at_least(&self, grams: &HashSet<Trigram>, min: usize) -> HashSet<&String> {
+        let mut counts: HashMap<&String, usize> = HashMap::new();
+        for gram in grams {
+            for name in self.trigrams.get(gram).into_iter().flatten() {
+                *counts.entry(name).or_default() += 1;
+            }
+        }
+        counts
+            .into_iter()
+            .filter_map(|(name, n)| (n >= min).then_some(name))
+            .collect()
+    }
+
+    /// Filenames that may match `query`, narrowed down without scanning every name wherever
+    /// the query has enough text to go on
+    fn candidates(&self, query: &Query) -> HashSet<&String> {
+        match query {
+            Query::Exact(x) => self.sorted.get(x).into_iter().collect(),
+            Query::Prefix(x) => self.with_prefix(x),
+            Query::Substring(x) => self.with_all(&trigrams(x, false, false)),
+            Query::Glob(x) => {
+                let grams = glob_literals(x)
+                    .iter()
+                    .flat_map(|(text, start, end)| trigrams(text, *start, *end))
+                    .collect();
+                self.with_all(&grams)
+            }
+            Query::Fuzzy(x) => {
+                // every edit destroys at most three trigrams of `x`
+                let grams = trigrams(x, true, true);
+                match grams.len().checked_sub(3 * Query::max_edits(x)) {
+                    Some(min) if min > 0 => self.with_at_least(&grams, min),
+                    _ => self.sorted.iter().collect(),
+                }
+            }
+        }
+    }
+}
+
+/// Index of filenames answering [Query]s through a sorted set and a trigram index
+#[derive(Default)]
+pub(crate) struct NameIndex {
+    names: RwLock<Names>,
+}
+
+impl NameIndex {
+    /// Add `filename` to the index
+    pub(crate) fn insert(&self, filename: &str) {
+        let mut names = self.names.write().unwrap();
+        if names.sorted.insert(filename.to_owned()) {
+            for gram in trigrams(filename, true, true) {
+                names
+                    .trigrams
+                    .entry(gram)
+                    .or_default()
+                    .insert(filename.to_owned());
+            }
+        }
+    }
+
+    /// Remove `filename` from the index
+    pub(crate) fn remove(&self, filename: &str) {
+        let mut names = self.names.write().unwrap();
+        if names.sorted.remove(filename) {
+            for gram in trigrams(filename, true, true) {
+                if let Some(x) = names.trigrams.get_mut(&gram) {
+                    x.remove(filename);
+                    if x.is_empty() {
+                        names.trigrams.remove(&gram);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Filenames matching `query`
+    pub(crate) fn find(&self, query: &Query) -> Vec<String> {
+        let Some(matcher) = query.matcher() else {
+            return Vec::new();
+        };
+
+        let names = self.names.read().unwrap();
+        names
+            .candidates(query)
+            .into_iter()
+            .filter(|x| matcher.matches(x))
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NAMES: [&str; 14] = [
+        "a",
+        "ab",
+        "abc",
+        "abd.txt",
+        "notes.txt",
+        "notes.md",
+        "docs/notes.txt",
+        "docs/report-2024.pdf",
+        "report.pdf",
+        "report-final.pdf",
+        "]odd[name",
+        "ünïcödé.txt",
+        "photo_001.jpg",
+        "photo_010.jpg",
+    ];
+
+    /// Index holding every one of [NAMES]
+    fn index() -> NameIndex {
+        let index = NameIndex::default();
+        for name in NAMES {
+            index.insert(name);
+        }
+        index
+    }
+
+    /// Sorted filenames `index` finds for `query`
+    fn found(index: &NameIndex, query: &Query) -> Vec<String> {
+        let mut found = index.find(query);
+        found.sort();
+        found
+    }
+
+    /// Sorted filenames of [NAMES] matching `query` when checked one by one
+    fn scanned(query: &Query) -> Vec<String> {
+        let mut scanned: Vec<_> = NAMES
+            .iter()
+            .filter(|x| query.matches(x))
+            .map(|x| x.to_string())
+            .collect();
+        scanned.sort();
+        scanned
+    }
+
+    #[test]
+    fn candidates_match_full_scan() {
+        let index = index();
+        let queries = [
+            "exact:notes.txt",
+            "exact:missing",
+            "prefix:",
+            "prefix:docs/",
+            "prefix:report",
+            "sub:",
+            "sub:a",
+            "sub:ot",
+            "sub:notes",
+            "sub:.pdf",
+            "sub:cöd",
+            "*",
+            "*.txt",
+            "notes.*",
+            "docs/*",
+            "*report*",
+            "photo_0?0.jpg",
+            "photo_0[01]?.jpg",
+            "[]]odd*",
+            "[!a]*",
+            "a?",
+            "fuzzy:a",
+            "fuzzy:abc",
+            "fuzzy:notes.tx",
+            "fuzzy:report.pdf",
+            "fuzzy:photo_011.jpg",
+            "fuzzy:unicode.txt",
+        ];
+        for text in queries {
+            let query: Query = text.parse().unwrap();
+            assert_eq!(found(&index, &query), scanned(&query), "{text}");
+        }
+    }
+
+    #[test]
+    fn removed_names_are_not_found() {
+        let index = index();
+        index.remove("notes.txt");
+        index.remove("missing");
+        let query = Query::Substring("notes".to_owned());
+        assert_eq!(found(&index, &query), ["docs/notes.txt", "notes.md"]);
+        assert!(index.find(&Query::Exact("notes.txt".to_owned())).is_empty());
+
+        // trigrams no name uses anymore are dropped along with it
+        for name in NAMES {
+            index.remove(name);
+        }
+        let names = index.names.read().unwrap();
+        assert!(names.sorted.is_empty() && names.trigrams.is_empty());
+    }
+
+    #[test]
+    fn glob_literals_are_anchored() {
+        let literal = |text: &str, start, end| (text.to_owned(), start, end);
+        assert_eq!(glob_literals("foo"), [literal("foo", true, true)]);
+        assert_eq!(
+            glob_literals("foo*bar"),
+            [literal("foo", true, false), literal("bar", false, true)]
+        );
+        assert_eq!(glob_literals("*.txt"), [literal(".txt", false, true)]);
+        assert_eq!(
+            glob_literals("a?b*"),
+            [
+                literal("a", true, false),
+                literal("b", false, false),
+                literal("", false, true)
+            ]
+        );
+        assert_eq!(
+            glob_literals("x[]y]z"),
+            [literal("x", true, false), literal("z", false, true)]
+        );
+        assert_eq!(glob_literals("[!ab]c"), [literal("c", false, true)]);
+    }
+
+    #[test]
+    fn fuzzy_finds_every_name_within_max_edits() {
+        let name = "report.txt";
+        assert_eq!(Query::max_edits(name), 2);
+
+        // every way of making up to two substitutions, deletions or insertions
+        let edits = |x: &str| {
+            let chars: Vec<_> = x.chars().collect();
+            let mut edited = Vec::new();
+            for i in 0..=chars.len() {
+                let mut inserted = chars.clone();
+                inserted.insert(i, 'x');
+                edited.push(inserted);
+                if i < chars.len() {
+                    let mut substituted = chars.clone();
+                    substituted[i] = 'x';
+                    edited.push(substituted);
+                    let mut deleted = chars.clone();
+                    deleted.remove(i);
+                    edited.push(deleted);
+                }
+            }
+            edited
+                .into_iter()
+                .map(|x| x.into_iter().collect::<String>())
+                .collect::<Vec<_>>()
+        };
+        let variants: BTreeSet<_> = edits(name).iter().flat_map(|x| edits(x)).collect();
+
+        let index = NameIndex::default();
+        for variant in &variants {
+            index.insert(variant);
+        }
+        index.insert("unrelated.pdf");
+        let found: BTreeSet<_> = index
+            .find(&Query::Fuzzy(name.to_owned()))
+            .into_iter()
+            .collect();
+        assert_eq!(found, variants);
+    }
+}
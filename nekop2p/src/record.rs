@@ -0,0 +1,61 @@
+use std::{collections::BTreeSet, time::SystemTime};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Metadata, PeerId};
+
+/// Description of a file as registered with an [crate::Indexer] by one of its holders
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq, Hash)]
+pub struct FileRecord {
+    /// Name the file is registered under
+    pub filename: String,
+
+    /// Size of the file in bytes
+    pub size: u64,
+
+    /// Hex-encoded SHA-256 digest of the file
+    pub digest: String,
+
+    /// Version of the file, as published by its origin
+    pub version: u8,
+
+    /// Peer the file originated from
+    pub origin: PeerId,
+
+    /// When the holder's copy was last modified
+    pub modified: SystemTime,
+
+    /// Only peers the holder lets download its copy, or `None` if anyone may
+    #[serde(default)]
+    pub readers: Option<BTreeSet<PeerId>>,
+}
+
+impl FileRecord {
+    /// Describe `filename` from its `metadata` and the time the holder's copy was `modified`,
+    /// readable by anyone
+    pub fn new(filename: String, metadata: &Metadata, modified: SystemTime) -> Self {
+        FileRecord {
+            filename,
+            size: metadata.size,
+            digest: metadata.digest.clone(),
+            version: metadata.version,
+            origin: metadata.origin,
+            modified,
+            readers: None,
+        }
+    }
+
+    /// Whether the holder lets `peer` download its copy
+    pub fn may_read(&self, peer: PeerId) -> bool {
+        self.readers.as_ref().is_none_or(|x| x.contains(&peer))
+    }
+
+    /// Whether the holder lets `peer` download its copy, where only files anyone may download
+    /// are visible to a peer that hasn't identified itself
+    pub fn visible_to(&self, peer: Option<PeerId>) -> bool {
+        match peer {
+            Some(x) => self.may_read(x),
+            None => self.readers.is_none(),
+        }
+    }
+}
@@ -1,12 +1,21 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
-use dashmap::{DashMap, DashSet};
 use delay_map::HashSetDelay;
-use tarpc::{client, context::Context, serde_transport::tcp, tokio_serde::formats::Bincode};
+use tarpc::{
+    client::{self, RpcError},
+    context::{self, Context},
+};
 use tokio::sync::RwLock;
 use uuid::Uuid;
 
-use crate::{Indexer, IndexerClient, PeerClient};
+use crate::{
+    handshake, hello::Session, identity::identify_message, Audits, Endpoint, Features, FileRecord,
+    Hello, Hit, IndexStore, Indexer, IndexerClient, Invalidation, Leases, NekoError, PeerClient,
+    PeerId, Query, Signature,
+};
 
 /// Reference [Indexer] implementation
 #[derive(Clone)]
@@ -14,11 +23,21 @@ pub struct IndexerServer {
     /// Address of the remote peer
     addr: SocketAddr,
 
-    /// Index shared between all connections
-    index: Arc<DashMap<String, DashSet<SocketAddr>>>,
+    /// Key and transport security this indexer connects to other nodes with
+    endpoint: Endpoint,
 
-    /// Index shared between all connections to map remote peers with their incoming download port
-    dl_ports: Arc<DashMap<SocketAddr, u16>>,
+    /// State of the connection, shared between its requests
+    session: Arc<Mutex<Session>>,
+
+    /// Index and download addresses shared between all connections
+    store: Arc<dyn IndexStore>,
+
+    /// Registration leases shared between all connections
+    leases: Arc<Leases>,
+
+    /// Outcomes of challenges to holders shared between all connections, if the indexer sends
+    /// any
+    audits: Option<Arc<Audits>>,
 
     /// List of neighboring superpeers
     neighbors: Arc<Vec<SocketAddr>>,
@@ -28,91 +47,111 @@ pub struct IndexerServer {
 }
 
 impl IndexerServer {
-    /// Create a new [IndexerServer] with a shared `index` and `dl_ports` for `addr`
+    /// Create a new [IndexerServer] with a shared `store` and `leases` for a connection from
+    /// `addr`, which proved to be `remote` if it is encrypted, identifying itself through
+    /// `endpoint`
     pub fn new(
         addr: SocketAddr,
-        index: &Arc<DashMap<String, DashSet<SocketAddr>>>,
-        dl_ports: &Arc<DashMap<SocketAddr, u16>>,
+        remote: Option<PeerId>,
+        endpoint: &Endpoint,
+        store: &Arc<dyn IndexStore>,
+        leases: &Arc<Leases>,
         neighbors: &Arc<Vec<SocketAddr>>,
         backtrace: &Arc<RwLock<HashSetDelay<Uuid>>>,
     ) -> Self {
         IndexerServer {
             addr,
-            index: Arc::clone(index),
-            dl_ports: Arc::clone(dl_ports),
+            endpoint: endpoint.clone(),
+            session: Arc::new(Mutex::new(Session::new(remote))),
+            store: Arc::clone(store),
+            leases: Arc::clone(leases),
+            audits: None,
             neighbors: Arc::clone(neighbors),
             backtrace: Arc::clone(backtrace),
         }
     }
 
-    /// Prints all entries in index
-    pub fn print_index(self) {
-        self.index.iter().for_each(|entry| {
-            let filename = entry.key();
-            entry.value().iter().for_each(|v| {
-                let peer = v.key();
-                println!("{filename}: {peer}");
-            });
-        });
+    /// Report the outcomes of challenges kept in the shared `audits` in search results, and
+    /// challenge every registration if they are set to
+    pub fn audits(mut self, audits: &Arc<Audits>) -> Self {
+        self.audits = Some(Arc::clone(audits));
+        self
     }
-}
 
-impl Indexer for IndexerServer {
-    async fn set_port(self, _: Context, dl_port: u16) {
-        self.dl_ports.insert(self.addr, dl_port);
+    /// Prints all entries in index
+    pub fn print_index(self) {
+        self.store.print();
     }
 
-    async fn register(self, _: Context, filename: String) {
-        println!("Registered {filename} for {0}", self.addr);
-        {
-            let list = self.index.entry(filename).or_default();
-            list.insert(self.addr);
-        }
-        self.print_index();
+    /// [Hello] this indexer introduces itself with
+    fn greeting(&self) -> Hello {
+        Hello::new(self.endpoint.id(), Features::NONE)
     }
 
-    async fn search(self, _: Context, filename: String) -> Vec<SocketAddr> {
-        println!("Searched {filename} for {0}", self.addr);
-        self.index
-            .entry(filename)
-            .or_default()
-            .iter()
-            .filter_map(|e| match self.dl_ports.get(&e) {
-                Some(x) => {
-                    let mut n = e.clone();
-                    n.set_port(*x);
-                    Some(n)
-                }
-                None => None,
-            })
-            .collect()
+    /// ID the remote peer proved through [Indexer::identify]
+    fn peer(&self) -> Result<PeerId, NekoError> {
+        self.session
+            .lock()
+            .unwrap()
+            .peer()
+            .ok_or(NekoError::Unidentified)
     }
 
-    async fn deregister(self, _: Context, filename: String) {
-        println!("Deregistered {filename} for {0}", self.addr);
-        {
-            let list = self.index.entry(filename).or_default();
-            list.remove(&self.addr);
+    /// Keep `client` connected to `addr` if its `answer` to `ours` shows it speaks a compatible
+    /// protocol
+    fn greeted<T>(
+        &self,
+        addr: &SocketAddr,
+        client: T,
+        ours: &Hello,
+        answer: Result<Result<Hello, NekoError>, RpcError>,
+    ) -> Option<T> {
+        match answer.map(|x| x.and_then(|x| ours.negotiate(&x))) {
+            Ok(Ok(_)) => Some(client),
+            Ok(Err(e)) => {
+                println!("Skipping {addr}: {e}");
+                None
+            }
+            Err(_) => {
+                println!("Skipping {addr}: no answer to hello");
+                None
+            }
         }
-        self.print_index();
     }
 
-    async fn disconnect_peer(self, _: Context) {
-        println!("Clean-up peer {0}", self.addr);
-
-        // scrub index of ip
-        self.index.iter().for_each(|entry| {
-            entry.value().remove(&self.addr);
-        });
-
-        // remove saved port
-        self.dl_ports.remove(&self.addr);
+    /// Connect to the neighboring indexer at `addr`, unless it speaks an incompatible protocol
+    async fn connect_neighbor(&self, addr: &SocketAddr) -> Option<IndexerClient> {
+        let transport = self.endpoint.connect(*addr, None).await.ok()?;
+        let client = IndexerClient::new(client::Config::default(), transport).spawn();
+        let ours = self.greeting();
+        let answer = client.hello(context::current(), ours).await;
+        self.greeted(addr, client, &ours, answer)
+    }
 
-        self.print_index();
+    /// Connect to the neighboring indexer at `addr` and prove both sides' identities, so it
+    /// accepts queries relayed through [Indexer::relay]
+    async fn connect_relay(&self, addr: &SocketAddr) -> Option<IndexerClient> {
+        let transport = self.endpoint.connect(*addr, None).await.ok()?;
+        let client = IndexerClient::new(client::Config::default(), transport).spawn();
+        match handshake(&client, self.endpoint.keypair(), Features::NONE).await {
+            Ok(_) => Some(client),
+            Err(e) => {
+                println!("Skipping {addr}: {e}");
+                None
+            }
+        }
     }
 
-    async fn query(self, c: Context, msg_id: Uuid, filename: String, ttl: u8) -> Vec<SocketAddr> {
-        println!("Querying {filename} for {0} (id: {msg_id})", self.addr);
+    /// Search the index and, while `ttl` lasts, the neighboring indexers for `query` on behalf
+    /// of `requester`, listing only holders it may download from
+    async fn gather(
+        &self,
+        c: Context,
+        msg_id: Uuid,
+        query: Query,
+        ttl: u8,
+        requester: Option<PeerId>,
+    ) -> Vec<Hit> {
         // if msg_id has already been seen, then we ignore the query
         if self.backtrace.read().await.contains_key(&msg_id) {
             println!("Message {msg_id} already handled!");
@@ -123,109 +162,292 @@ impl Indexer for IndexerServer {
         self.backtrace.write().await.insert(msg_id);
 
         // get peers from this peer's index
-        println!("Searched {filename} for {0}", self.addr);
-        let mut peers: Vec<_> = self
-            .index
-            .entry(filename.clone())
-            .or_default()
-            .iter()
-            .filter_map(|e| match self.dl_ports.get(&e) {
-                Some(x) => {
-                    let mut n = e.clone();
-                    n.set_port(*x);
-                    Some(n)
-                }
-                None => None,
-            })
-            .collect();
+        println!("Searched {query} for {0}", self.addr);
+        let mut hits = self.store.search(&query);
+        self.annotate(&mut hits);
 
         // propogate query to neighboring peers
         if ttl > 0 {
             for peer in self.neighbors.iter() {
-                println!(
-                    "Propagating query of {filename} to {0} (id: {msg_id})",
-                    peer
-                );
-                if let Ok(transport) = tcp::connect(peer, Bincode::default).await {
-                    let client = IndexerClient::new(client::Config::default(), transport).spawn();
-                    peers.append(
-                        &mut client
-                            .query(c, msg_id, filename.clone(), ttl - 1)
-                            .await
-                            .unwrap_or_default(),
-                    );
+                println!("Propagating query of {query} to {0} (id: {msg_id})", peer);
+                if let Some(client) = self.connect_relay(peer).await {
+                    let relayed = client
+                        .relay(c, msg_id, query.clone(), ttl - 1, requester)
+                        .await;
+                    hits.extend(relayed.ok().and_then(Result::ok).unwrap_or_default());
                 }
             }
         }
 
-        peers
+        hits.retain(|x| x.record.visible_to(requester));
+        hits
+    }
+
+    /// Connect to the peer `holder` downloading from `addr`, unless it speaks an incompatible
+    /// protocol or turns out to be another peer
+    async fn connect_peer(&self, holder: PeerId, addr: &SocketAddr) -> Option<PeerClient> {
+        let transport = self.endpoint.connect(*addr, Some(holder)).await.ok()?;
+        let client = PeerClient::new(client::Config::default(), transport).spawn();
+        let ours = self.greeting();
+        let answer = client.hello(context::current(), ours).await;
+        self.greeted(addr, client, &ours, answer)
+    }
+
+    /// Fill in how each of `hits` fared in its last challenge
+    fn annotate(&self, hits: &mut [Hit]) {
+        if let Some(audits) = &self.audits {
+            audits.annotate(hits);
+        }
+    }
+
+    /// Challenge `peer` in the background to prove it has the files described in `records`, if
+    /// the indexer challenges registrations
+    fn challenge(&self, peer: PeerId, records: Vec<FileRecord>) {
+        let Some(audits) = self.audits.as_ref().filter(|x| x.on_register()) else {
+            return;
+        };
+        let Some(addr) = self.store.dl_addr(peer) else {
+            return;
+        };
+        let audits = Arc::clone(audits);
+        tokio::spawn(async move { audits.challenge(peer, addr, &records).await });
+    }
+
+    /// Forget the outcomes of challenges for `filenames` from `peer`
+    fn forget<'a>(&self, peer: PeerId, filenames: impl IntoIterator<Item = &'a String>) {
+        if let Some(audits) = &self.audits {
+            for filename in filenames {
+                audits.forget(peer, filename);
+            }
+        }
+    }
+
+    /// Make sure the remote peer is identified and can be downloaded from before it registers
+    /// `records`, returning its ID
+    fn check_registrable<'a>(
+        &self,
+        records: impl IntoIterator<Item = &'a FileRecord>,
+    ) -> Result<PeerId, NekoError> {
+        let peer = self.peer()?;
+        if self.store.dl_addr(peer).is_none() {
+            println!("Refused registration from {peer} without a port");
+            return Err(NekoError::UnknownPeer);
+        }
+        match records.into_iter().find(|x| x.filename.is_empty()) {
+            Some(x) => Err(NekoError::InvalidName(x.filename.clone())),
+            None => Ok(peer),
+        }
+    }
+}
+
+impl Indexer for IndexerServer {
+    async fn hello(self, _: Context, hello: Hello) -> Result<Hello, NekoError> {
+        let ours = self.greeting();
+        match ours.negotiate(&hello) {
+            Ok(x) => println!(
+                "Hello from node {0} at {1} (features: {x})",
+                hello.node_id, self.addr
+            ),
+            Err(e) => {
+                println!("Refused node {0} at {1}: {e}", hello.node_id, self.addr);
+                return Err(e);
+            }
+        }
+
+        if let Err(e) = self.session.lock().unwrap().greet(hello, ours) {
+            println!(
+                "Refused node {0} at {1}: connection belongs to another node",
+                hello.node_id, self.addr
+            );
+            return Err(e);
+        }
+        Ok(ours)
+    }
+
+    async fn identify(self, _: Context, signature: Signature) -> Result<Signature, NekoError> {
+        let identified = self.session.lock().unwrap().identify(&signature);
+        let (theirs, _) = match identified {
+            Ok(x) => x,
+            Err(e) => {
+                println!("Refused identification from {0}: {e}", self.addr);
+                return Err(e);
+            }
+        };
+
+        println!("Identified {0} at {1}", theirs.node_id, self.addr);
+        Ok(self
+            .endpoint
+            .keypair()
+            .sign(&identify_message(&theirs.node_id, &theirs.challenge)))
+    }
+
+    async fn set_port(self, _: Context, dl_port: u16) -> Result<(), NekoError> {
+        let peer = self.peer()?;
+        if dl_port == 0 {
+            return Err(NekoError::InvalidPort(dl_port));
+        }
+        self.store
+            .set_addr(peer, SocketAddr::new(self.addr.ip(), dl_port));
+        self.leases.grant(peer);
+        Ok(())
+    }
+
+    async fn heartbeat(self, _: Context) -> Result<u64, NekoError> {
+        self.leases
+            .renew(self.peer()?)
+            .then(|| self.leases.length().as_secs())
+            .ok_or(NekoError::LeaseLapsed)
+    }
+
+    async fn register(self, _: Context, record: FileRecord) -> Result<(), NekoError> {
+        let peer = self.check_registrable([&record])?;
+        println!("Registered {0} for {peer}", record.filename);
+        self.store.register(&record, peer);
+        self.leases.grant(peer);
+        self.challenge(peer, vec![record]);
+        self.print_index();
+        Ok(())
+    }
+
+    async fn search(self, _: Context, query: Query) -> Vec<Hit> {
+        println!("Searched {query} for {0}", self.addr);
+        let mut hits = self.store.search(&query);
+        self.annotate(&mut hits);
+
+        // peers that haven't identified themselves only see holders anyone may download from
+        let requester = self.peer().ok();
+        hits.retain(|x| x.record.visible_to(requester));
+        hits
+    }
+
+    async fn deregister(self, _: Context, filename: String) -> Result<(), NekoError> {
+        let peer = self.peer()?;
+        if !self.store.deregister(&filename, peer) {
+            return Err(NekoError::NotRegistered(filename));
+        }
+        self.forget(peer, [&filename]);
+        println!("Deregistered {filename} for {peer}");
+        self.print_index();
+        Ok(())
+    }
+
+    async fn register_many(self, _: Context, records: Vec<FileRecord>) -> Result<(), NekoError> {
+        let peer = self.check_registrable(&records)?;
+        println!("Registered {0} files for {peer}", records.len());
+        for record in records.iter() {
+            self.store.register(record, peer);
+        }
+        self.leases.grant(peer);
+        self.challenge(peer, records);
+        self.print_index();
+        Ok(())
+    }
+
+    async fn deregister_many(self, _: Context, filenames: Vec<String>) -> Result<(), NekoError> {
+        let peer = self.peer()?;
+        println!("Deregistered {0} files for {peer}", filenames.len());
+        for filename in filenames.iter() {
+            self.store.deregister(filename, peer);
+        }
+        self.forget(peer, &filenames);
+        self.print_index();
+        Ok(())
+    }
+
+    async fn disconnect_peer(self, _: Context) {
+        let Ok(peer) = self.peer() else {
+            return;
+        };
+        println!("Clean-up peer {peer}");
+        self.store.remove_peer(peer);
+        self.leases.release(peer);
+        if let Some(audits) = &self.audits {
+            audits.forget_peer(peer);
+        }
+        self.print_index();
+    }
+
+    async fn query(self, c: Context, msg_id: Uuid, query: Query, ttl: u8) -> Vec<Hit> {
+        println!("Querying {query} for {0} (id: {msg_id})", self.addr);
+        // peers that haven't identified themselves only see holders anyone may download from
+        self.gather(c, msg_id, query, ttl, self.peer().ok()).await
+    }
+
+    async fn relay(
+        self,
+        c: Context,
+        msg_id: Uuid,
+        query: Query,
+        ttl: u8,
+        requester: Option<PeerId>,
+    ) -> Result<Vec<Hit>, NekoError> {
+        let neighbor = self.peer()?;
+        println!("Querying {query} relayed by {neighbor} (id: {msg_id})");
+        Ok(self.gather(c, msg_id, query, ttl, requester).await)
     }
 
     async fn invalidate(
         self,
         c: Context,
         msg_id: Uuid,
-        origin_server: SocketAddr,
-        filename: String,
-    ) {
+        invalidation: Invalidation,
+    ) -> Result<(), NekoError> {
+        let Invalidation {
+            origin,
+            ref filename,
+            ..
+        } = invalidation;
         println!(
             "Invalidation message for {filename}::{0} sent by {1} (id: {msg_id})",
-            origin_server, self.addr
+            origin, self.addr
         );
         // if msg_id has already been seen, then we ignore the query
         if self.backtrace.read().await.contains_key(&msg_id) {
             println!("Message {msg_id} already handled!");
-            return;
+            return Err(NekoError::InvalidationRejected(format!(
+                "message {msg_id} already handled"
+            )));
+        }
+
+        // only the origin can invalidate its files, whoever relays the invalidation
+        if !invalidation.verify() {
+            println!("Refused forged invalidation of {filename} for {origin}");
+            return Err(NekoError::BadSignature);
+        }
+
+        // a peer may only invalidate files it is the origin of
+        if let Ok(peer) = self.peer() {
+            if peer != origin {
+                println!("Refused invalidation of {filename} for {origin} from {peer}");
+                return Err(NekoError::InvalidationRejected(format!(
+                    "{peer} is not the origin {origin}"
+                )));
+            }
         }
 
         // insert into set of seen msg_ids
         self.backtrace.write().await.insert(msg_id);
 
-        // send invalidation message to leaf nodes
+        // send invalidation message to leaf nodes holding older replicas from the same origin,
+        // leaving the origin and unrelated files of the same name alone
         println!("Searched {filename} for {0}", self.addr);
-        for peer in self
-            .index
-            .entry(filename.clone())
-            .or_default()
-            .iter()
-            .filter_map(|e| match self.dl_ports.get(&e) {
-                Some(x) => {
-                    let mut n = e.clone();
-                    n.set_port(*x);
-                    Some(n)
-                }
-                None => None,
-            })
+        let stale = self
+            .store
+            .search(&Query::Exact(filename.clone()))
             .into_iter()
-        {
-            if peer == origin_server {
-                // skip original leaf node
-                continue;
-            }
+            .filter(|x| invalidation.supersedes(x.holder, &x.record));
+        for Hit { holder, peer, .. } in stale {
             println!(
                 "Propagating invalidation of {filename} to {0} (id: {msg_id})",
                 peer
             );
-            if let Ok(transport) = tcp::connect(peer, Bincode::default).await {
-                let client = PeerClient::new(client::Config::default(), transport).spawn();
-                let _ = client
-                    .invalidate(c, msg_id, origin_server, filename.clone())
-                    .await;
+            if let Some(client) = self.connect_peer(holder, &peer).await {
+                let _ = client.invalidate(c, msg_id, invalidation.clone()).await;
             }
         }
 
-        // invalidate all leaf nodes that weren't the origin server
-        self.index
-            .entry(filename.clone())
-            .or_default()
-            .retain(|e| match self.dl_ports.get(&e) {
-                Some(x) => {
-                    let mut n = e.clone();
-                    n.set_port(*x);
-                    n == origin_server
-                }
-                None => false,
-            });
+        // drop the older replicas from the index
+        self.store.invalidate(&invalidation);
 
         // propogate invalidation to neighboring indexers
         for peer in self.neighbors.iter() {
@@ -233,12 +455,10 @@ impl Indexer for IndexerServer {
                 "Propagating query of {filename} to {0} (id: {msg_id})",
                 peer
             );
-            if let Ok(transport) = tcp::connect(peer, Bincode::default).await {
-                let client = IndexerClient::new(client::Config::default(), transport).spawn();
-                let _ = client
-                    .invalidate(c, msg_id, origin_server, filename.clone())
-                    .await;
+            if let Some(client) = self.connect_neighbor(peer).await {
+                let _ = client.invalidate(c, msg_id, invalidation.clone()).await;
             }
         }
+        Ok(())
     }
 }
@@ -0,0 +1,152 @@
+use std::{
+    collections::BTreeSet,
+    io,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::PeerId;
+
+/// Set of directories a [crate::PeerServer] is allowed to serve files from
+///
+/// Filenames are always relative to one of the share directories. Absolute paths, `..`
+/// components and symlinks leading outside of the share directories are treated as if the file
+/// does not exist, as are the `.meta`, `.part` and `.progress` sidecars kept next to shared
+/// files and files hidden with [ShareRoot::hide]. Files restricted with
+/// [ShareRoot::restrict] are only served to the peers allowed to read them.
+#[derive(Clone, Debug)]
+pub struct ShareRoot {
+    /// Canonicalized share directories, searched in order
+    dirs: Vec<PathBuf>,
+
+    /// Canonicalized files inside the share directories that are never shared
+    hidden: Vec<PathBuf>,
+
+    /// Canonicalized files and directories along with the only peers allowed to read them
+    restricted: Vec<(PathBuf, BTreeSet<PeerId>)>,
+}
+
+/// Suffixes of the files kept next to shared files, which are never shared themselves
+const SIDECARS: [&str; 3] = [".meta", ".part", ".progress"];
+
+/// Whether `path` is one of the [SIDECARS] kept next to a shared file
+pub(crate) fn is_sidecar(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| SIDECARS.iter().any(|s| x.ends_with(s)))
+}
+
+/// Path of the `suffix` sidecar file (e.g. `.meta`) belonging to `path`
+pub fn sidecar(path: &Path, suffix: &str) -> PathBuf {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(suffix);
+    sidecar.into()
+}
+
+/// Normalise a remote-supplied `filename` into a relative path, rejecting anything that could
+/// escape a share directory lexically
+fn relative(filename: &str) -> Option<PathBuf> {
+    let mut rel = PathBuf::new();
+    for component in Path::new(filename).components() {
+        match component {
+            Component::Normal(x) => rel.push(x),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => return None,
+        }
+    }
+
+    if rel.as_os_str().is_empty() {
+        None
+    } else {
+        Some(rel)
+    }
+}
+
+impl ShareRoot {
+    /// Create a [ShareRoot] from existing `dirs`
+    pub fn new<P: AsRef<Path>>(dirs: impl IntoIterator<Item = P>) -> io::Result<Self> {
+        let dirs = dirs
+            .into_iter()
+            .map(|d| d.as_ref().canonicalize())
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ShareRoot {
+            dirs,
+            hidden: Vec::new(),
+            restricted: Vec::new(),
+        })
+    }
+
+    /// Never share the existing file at `path`, such as a private key kept inside a share
+    /// directory
+    pub fn hide(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        self.hidden.push(path.as_ref().canonicalize()?);
+        Ok(self)
+    }
+
+    /// Whether the file at `path` is hidden with [ShareRoot::hide]
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        path.canonicalize().is_ok_and(|x| self.hidden.contains(&x))
+    }
+
+    /// Only let `readers` download the existing file at `path`, or every file inside it if it is
+    /// a directory such as a whole share directory
+    ///
+    /// Where rules overlap, the one for the longest path applies.
+    pub fn restrict(
+        mut self,
+        path: impl AsRef<Path>,
+        readers: impl IntoIterator<Item = PeerId>,
+    ) -> io::Result<Self> {
+        self.restricted
+            .push((path.as_ref().canonicalize()?, readers.into_iter().collect()));
+        Ok(self)
+    }
+
+    /// Peers allowed to download the resolved file at `path`, or `None` if anyone may
+    pub fn readers(&self, path: &Path) -> Option<&BTreeSet<PeerId>> {
+        self.restricted
+            .iter()
+            .filter(|(x, _)| path.starts_with(x))
+            .max_by_key(|(x, _)| x.components().count())
+            .map(|(_, readers)| readers)
+    }
+
+    /// Share directories, in the order they are searched
+    pub fn dirs(&self) -> &[PathBuf] {
+        &self.dirs
+    }
+
+    /// Resolve `filename` to an existing file inside one of the share directories
+    pub fn resolve(&self, filename: &str) -> Option<PathBuf> {
+        let rel = relative(filename)?;
+        if is_sidecar(&rel) {
+            return None;
+        }
+        self.dirs.iter().find_map(|dir| {
+            // canonicalize follows symlinks, so anything pointing outside (or at a sidecar) is
+            // caught here
+            let path = dir.join(&rel).canonicalize().ok()?;
+            (path.starts_with(dir)
+                && path.is_file()
+                && !is_sidecar(&path)
+                && !self.hidden.contains(&path))
+            .then_some(path)
+        })
+    }
+
+    /// Path `filename` should be stored at, which is where it already exists or else inside the
+    /// first share directory
+    pub fn local_path(&self, filename: &str) -> Option<PathBuf> {
+        if let Some(path) = self.resolve(filename) {
+            return Some(path);
+        }
+
+        let rel = relative(filename)?;
+        if is_sidecar(&rel) {
+            return None;
+        }
+        let dir = self.dirs.first()?;
+        let parent = dir.join(&rel).parent()?.canonicalize().ok()?;
+        let path = parent.join(rel.file_name()?);
+        (parent.starts_with(dir) && !self.hidden.contains(&path)).then_some(path)
+    }
+}
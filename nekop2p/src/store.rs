@@ -0,0 +1,257 @@
+use std::{io, net::SocketAddr, path::Path};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+
+use crate::{
+    journal::Journal, query::NameIndex, FileRecord, Hit, Invalidation, PeerId, Query, Verification,
+};
+
+/// Storage backend behind an [crate::IndexerServer]
+///
+/// Peers are identified by their [PeerId]. Lookups return the address other peers should
+/// download from as well, which is the last one the peer set through [IndexStore::set_addr].
+pub trait IndexStore: Send + Sync {
+    /// Record `dl_addr` as the address other peers download from `peer` at
+    fn set_addr(&self, peer: PeerId, dl_addr: SocketAddr);
+
+    /// Address other peers download from `peer` at, if it has set one
+    fn dl_addr(&self, peer: PeerId) -> Option<SocketAddr>;
+
+    /// Register the file described by `record` for `peer`
+    fn register(&self, record: &FileRecord, peer: PeerId);
+
+    /// Deregister `filename` for `peer`, returning whether it held it
+    fn deregister(&self, filename: &str, peer: PeerId) -> bool;
+
+    /// All peers holding `filename`, with the addresses they download from
+    fn lookup(&self, filename: &str) -> Vec<(PeerId, SocketAddr)>;
+
+    /// Every peer holding a filename matching `query`, with the [FileRecord] it registered
+    fn search(&self, query: &Query) -> Vec<Hit>;
+
+    /// Remove all mentions of `peer`, including its address
+    fn remove_peer(&self, peer: PeerId);
+
+    /// Drop every registration superseded by `invalidation`
+    fn invalidate(&self, invalidation: &Invalidation);
+
+    /// Prints all entries in the store
+    fn print(&self);
+}
+
+/// Default in-memory [IndexStore] backed by [DashMap]s
+#[derive(Default)]
+pub struct MemoryStore {
+    /// Peers holding each filename, with the [FileRecord] each of them registered
+    index: DashMap<String, DashMap<PeerId, FileRecord>>,
+
+    /// Filenames in `index`, for answering [Query]s without scanning every key
+    names: NameIndex,
+
+    /// Map of peers to the address they download from
+    dl_addrs: DashMap<PeerId, SocketAddr>,
+}
+
+impl MemoryStore {
+    /// Files registered by `peer`
+    fn records_of(&self, peer: PeerId) -> Vec<FileRecord> {
+        self.index
+            .iter()
+            .filter_map(|e| e.value().get(&peer).map(|x| x.clone()))
+            .collect()
+    }
+
+    /// Forget `filename` once nobody holds it
+    fn prune(&self, filename: &str) {
+        if let Entry::Occupied(entry) = self.index.entry(filename.to_owned()) {
+            if entry.get().is_empty() {
+                self.names.remove(filename);
+                entry.remove();
+            }
+        }
+    }
+}
+
+impl IndexStore for MemoryStore {
+    fn set_addr(&self, peer: PeerId, dl_addr: SocketAddr) {
+        self.dl_addrs.insert(peer, dl_addr);
+    }
+
+    fn dl_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.dl_addrs.get(&peer).map(|x| *x)
+    }
+
+    fn register(&self, record: &FileRecord, peer: PeerId) {
+        // hold the entry until the name is indexed so a concurrent prune can't miss it
+        let list = self.index.entry(record.filename.clone()).or_default();
+        list.insert(peer, record.clone());
+        self.names.insert(&record.filename);
+    }
+
+    fn deregister(&self, filename: &str, peer: PeerId) -> bool {
+        let removed = self
+            .index
+            .get(filename)
+            .is_some_and(|list| list.remove(&peer).is_some());
+        self.prune(filename);
+        removed
+    }
+
+    fn lookup(&self, filename: &str) -> Vec<(PeerId, SocketAddr)> {
+        self.index
+            .get(filename)
+            .map(|list| {
+                list.iter()
+                    .filter_map(|e| Some((*e.key(), self.dl_addr(*e.key())?)))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    fn search(&self, query: &Query) -> Vec<Hit> {
+        let mut hits = Vec::new();
+        for filename in self.names.find(query) {
+            if let Some(list) = self.index.get(&filename) {
+                hits.extend(list.iter().filter_map(|e| {
+                    self.dl_addr(*e.key()).map(|peer| Hit {
+                        record: e.value().clone(),
+                        holder: *e.key(),
+                        peer,
+                        verification: Verification::Unverified,
+                    })
+                }));
+            }
+        }
+        hits
+    }
+
+    fn remove_peer(&self, peer: PeerId) {
+        // scrub index of peer
+        for record in self.records_of(peer) {
+            self.deregister(&record.filename, peer);
+        }
+
+        // remove saved address
+        self.dl_addrs.remove(&peer);
+    }
+
+    fn invalidate(&self, invalidation: &Invalidation) {
+        let filename = &invalidation.filename;
+        if let Some(list) = self.index.get(filename) {
+            list.retain(|holder, record| !invalidation.supersedes(*holder, record));
+        }
+        self.prune(filename);
+    }
+
+    fn print(&self) {
+        self.index.iter().for_each(|entry| {
+            let filename = entry.key();
+            entry.value().iter().for_each(|v| {
+                let peer = v.key();
+                println!("{filename}: {peer}");
+            });
+        });
+    }
+}
+
+/// On-disk [IndexStore] that keeps a [MemoryStore] and records every change in a snapshot file
+/// plus an append-only log
+///
+/// Registrations restored from disk stay unconfirmed until their peer reconnects under the same
+/// [PeerId] and sets its address, or until they are dropped with [DiskStore::expire].
+pub struct DiskStore {
+    /// Live registrations
+    memory: MemoryStore,
+
+    /// Durable record of registrations
+    journal: Journal,
+}
+
+impl DiskStore {
+    /// Open the store with its snapshot at `path`, restoring its registrations as unconfirmed
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        Ok(DiskStore {
+            memory: MemoryStore::default(),
+            journal: Journal::open(path)?,
+        })
+    }
+
+    /// Drop every registration that is still unconfirmed, returning the peers that were dropped
+    pub fn expire(&self) -> Vec<PeerId> {
+        self.journal.expire()
+    }
+}
+
+impl IndexStore for DiskStore {
+    fn set_addr(&self, peer: PeerId, dl_addr: SocketAddr) {
+        self.memory.set_addr(peer, dl_addr);
+        self.journal.set_addr(peer, dl_addr);
+
+        // a peer we knew before a restart is back, so its registrations are live again
+        let restored = self.journal.confirm(peer);
+        if !restored.is_empty() {
+            println!(
+                "Confirmed {0} restored registrations for {peer}",
+                restored.len()
+            );
+        }
+        for record in restored {
+            self.memory.register(&record, peer);
+        }
+    }
+
+    fn dl_addr(&self, peer: PeerId) -> Option<SocketAddr> {
+        self.memory.dl_addr(peer)
+    }
+
+    fn register(&self, record: &FileRecord, peer: PeerId) {
+        self.journal.register(record, peer);
+        self.memory.register(record, peer);
+    }
+
+    fn deregister(&self, filename: &str, peer: PeerId) -> bool {
+        self.journal.deregister(filename, peer);
+        self.memory.deregister(filename, peer)
+    }
+
+    fn lookup(&self, filename: &str) -> Vec<(PeerId, SocketAddr)> {
+        let mut peers = self.memory.lookup(filename);
+        for peer in self.journal.unconfirmed_holders(filename) {
+            if !peers.contains(&peer) {
+                peers.push(peer);
+            }
+        }
+        peers
+    }
+
+    fn search(&self, query: &Query) -> Vec<Hit> {
+        let mut hits = self.memory.search(query);
+        for hit in self.journal.unconfirmed_matches(query) {
+            if !hits.contains(&hit) {
+                hits.push(hit);
+            }
+        }
+        hits
+    }
+
+    fn remove_peer(&self, peer: PeerId) {
+        self.journal.disconnect(peer);
+        self.memory.remove_peer(peer);
+    }
+
+    fn invalidate(&self, invalidation: &Invalidation) {
+        let filename = &invalidation.filename;
+        for hit in self.memory.search(&Query::Exact(filename.clone())) {
+            if invalidation.supersedes(hit.holder, &hit.record) {
+                self.journal.deregister(filename, hit.holder);
+            }
+        }
+        self.memory.invalidate(invalidation);
+        self.journal.drop_superseded(invalidation);
+    }
+
+    fn print(&self) {
+        self.memory.print();
+        self.journal.print_unconfirmed();
+    }
+}
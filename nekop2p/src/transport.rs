@@ -0,0 +1,472 @@
+//! TCP connections carrying RPCs, optionally encrypted and authenticated with Noise
+use std::{
+    io::{self, ErrorKind},
+    marker::PhantomData,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use serde::{Deserialize, Serialize};
+use snow::{Builder, HandshakeState, StatelessTransportState};
+use tarpc::{serde_transport, tokio_serde::formats::Bincode};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream, ReadBuf},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time::timeout,
+};
+
+use crate::{Keypair, NetworkKey, PeerId, Signature};
+
+/// Noise pattern used by [Security::Noise], which exchanges and authenticates both static keys
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_BLAKE2s";
+
+/// Largest Noise message, including its authentication tag
+const MAX_MESSAGE: usize = 65535;
+
+/// Largest plaintext sealed into a single Noise message
+const MAX_PLAINTEXT: usize = MAX_MESSAGE - 16;
+
+/// How long a connection may take to finish its Noise handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How connections between nodes are protected
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Security {
+    /// RPCs are sent as plain bincode, readable and forgeable by anyone on the network
+    #[default]
+    Plaintext,
+
+    /// Every connection starts with a Noise handshake proving the [PeerId] of both sides, and
+    /// RPCs are encrypted from then on
+    Noise,
+}
+
+/// Byte stream underneath a [Connection]
+enum Stream {
+    /// Plain TCP
+    Plain(TcpStream),
+
+    /// Plaintext side of the tasks sealing and opening Noise messages on the TCP stream
+    Noise(DuplexStream),
+}
+
+/// An established connection to another node
+pub struct Connection {
+    /// Bytes to and from the node
+    stream: Stream,
+
+    /// Address of the node
+    peer_addr: SocketAddr,
+
+    /// ID the node proved during the Noise handshake, if there was one
+    remote: Option<PeerId>,
+}
+
+impl Connection {
+    /// Address of the node on the other end
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// ID the node on the other end proved during the Noise handshake, or `None` over
+    /// [Security::Plaintext]
+    pub fn remote(&self) -> Option<PeerId> {
+        self.remote
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(x) => Pin::new(x).poll_read(cx, buf),
+            Stream::Noise(x) => Pin::new(x).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(x) => Pin::new(x).poll_write(cx, buf),
+            Stream::Noise(x) => Pin::new(x).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(x) => Pin::new(x).poll_flush(cx),
+            Stream::Noise(x) => Pin::new(x).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().stream {
+            Stream::Plain(x) => Pin::new(x).poll_shutdown(cx),
+            Stream::Noise(x) => Pin::new(x).poll_shutdown(cx),
+        }
+    }
+}
+
+/// [tarpc] transport over a [Connection]
+pub type Transport<Item, SinkItem> =
+    serde_transport::Transport<Connection, Item, SinkItem, Bincode<Item, SinkItem>>;
+
+/// Stream of [Transport]s accepted by [Endpoint::listen] that finished their handshake
+pub struct Listener<Item, SinkItem> {
+    /// Address the listener is bound to
+    local_addr: SocketAddr,
+
+    /// Connections handed over by the accept loop
+    incoming: mpsc::UnboundedReceiver<Connection>,
+
+    /// Message types of the RPCs carried
+    rpc: PhantomData<fn() -> (Item, SinkItem)>,
+}
+
+impl<Item, SinkItem> Listener<Item, SinkItem> {
+    /// Address the listener is bound to
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
+
+impl<Item, SinkItem> futures::Stream for Listener<Item, SinkItem>
+where
+    Item: for<'de> Deserialize<'de>,
+    SinkItem: Serialize,
+{
+    type Item = Transport<Item, SinkItem>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.incoming
+            .poll_recv(cx)
+            .map(|x| x.map(|conn| Transport::from((conn, Bincode::default()))))
+    }
+}
+
+/// Map a Noise failure onto an [io::Error]
+fn noise_error(e: snow::Error) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, e)
+}
+
+/// What a node signs to tie the Noise `static_key` of a connection to its [PeerId]
+fn noise_message(static_key: &[u8]) -> Vec<u8> {
+    [b"nekop2p noise".as_slice(), static_key].concat()
+}
+
+/// Send a handshake message with its length in front
+async fn send(tcp: &mut TcpStream, message: &[u8]) -> io::Result<()> {
+    tcp.write_u16(message.len() as u16).await?;
+    tcp.write_all(message).await
+}
+
+/// Receive a handshake message sent with [send]
+async fn recv(tcp: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut message = vec![0; tcp.read_u16().await?.into()];
+    tcp.read_exact(&mut message).await?;
+    Ok(message)
+}
+
+/// Seal every write to the returned stream into Noise messages on `tcp`, and open every message
+/// coming in on `tcp` for reading from it
+fn seal(tcp: TcpStream, noise: StatelessTransportState) -> DuplexStream {
+    let (ours, theirs) = tokio::io::duplex(4 * MAX_MESSAGE);
+    let (mut plain_rx, mut plain_tx) = tokio::io::split(theirs);
+    // every message goes out in a single write, so there is nothing for Nagle to coalesce
+    let _ = tcp.set_nodelay(true);
+    let (mut tcp_rx, mut tcp_tx) = tcp.into_split();
+    let noise = Arc::new(noise);
+
+    let sealer = Arc::clone(&noise);
+    tokio::spawn(async move {
+        let (mut plain, mut sealed) = (vec![0; MAX_PLAINTEXT], vec![0; 2 + MAX_MESSAGE]);
+        for nonce in 0.. {
+            let n = match plain_rx.read(&mut plain).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => n,
+            };
+            let Ok(len) = sealer.write_message(nonce, &plain[..n], &mut sealed[2..]) else {
+                break;
+            };
+            sealed[..2].copy_from_slice(&(len as u16).to_be_bytes());
+            if tcp_tx.write_all(&sealed[..2 + len]).await.is_err() {
+                break;
+            }
+        }
+        let _ = tcp_tx.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        let (mut plain, mut sealed) = (vec![0; MAX_MESSAGE], vec![0; MAX_MESSAGE]);
+        for nonce in 0.. {
+            let Ok(len) = tcp_rx.read_u16().await.map(usize::from) else {
+                break;
+            };
+            if tcp_rx.read_exact(&mut sealed[..len]).await.is_err() {
+                break;
+            }
+            // a message that fails to open was tampered with, so drop the connection
+            let Ok(n) = noise.read_message(nonce, &sealed[..len], &mut plain) else {
+                break;
+            };
+            if plain_tx.write_all(&plain[..n]).await.is_err() {
+                break;
+            }
+        }
+        let _ = plain_tx.shutdown().await;
+    });
+
+    ours
+}
+
+/// Key and [Security] a node opens and accepts connections with
+#[derive(Clone)]
+pub struct Endpoint {
+    /// Key behind the [PeerId] this node proves in the Noise handshake
+    keypair: Arc<Keypair>,
+
+    /// Whether connections are encrypted
+    security: Security,
+
+    /// Key of the private network this node only talks within, if any
+    network: Option<NetworkKey>,
+}
+
+impl Endpoint {
+    /// Create an [Endpoint] for the node holding `keypair`, protecting connections with
+    /// `security`
+    pub fn new(keypair: &Arc<Keypair>, security: Security) -> Self {
+        Endpoint {
+            keypair: Arc::clone(keypair),
+            security,
+            network: None,
+        }
+    }
+
+    /// Only talk to nodes proving they hold `network` when connecting
+    pub fn network(mut self, network: NetworkKey) -> Self {
+        self.network = Some(network);
+        self
+    }
+
+    /// Key of this node
+    pub fn keypair(&self) -> &Arc<Keypair> {
+        &self.keypair
+    }
+
+    /// [PeerId] of this node
+    pub fn id(&self) -> PeerId {
+        self.keypair.id()
+    }
+
+    /// How connections are protected
+    pub fn security(&self) -> Security {
+        self.security
+    }
+
+    /// Handshake state for a new connection, with a fresh static key
+    fn noise(&self, initiator: bool) -> io::Result<(HandshakeState, Vec<u8>)> {
+        let params = NOISE_PARAMS.parse().map_err(noise_error)?;
+        let builder = Builder::new(params);
+        let static_key = builder.generate_keypair().map_err(noise_error)?;
+
+        // sign the static key so the other side knows which node holds it
+        let signature = self.keypair.sign(&noise_message(&static_key.public));
+        let credentials = [self.id().as_bytes().as_slice(), &signature.to_bytes()].concat();
+
+        let builder = builder.local_private_key(&static_key.private);
+        let state = if initiator {
+            builder.build_initiator()
+        } else {
+            builder.build_responder()
+        };
+        Ok((state.map_err(noise_error)?, credentials))
+    }
+
+    /// Check the `credentials` the other side of `state` sent, returning its [PeerId]
+    fn verify(state: &HandshakeState, credentials: &[u8]) -> io::Result<PeerId> {
+        let refused = || io::Error::new(ErrorKind::PermissionDenied, "bad noise credentials");
+        let static_key = state.get_remote_static().ok_or_else(refused)?;
+        let (id, signature) = credentials.split_at_checked(32).ok_or_else(refused)?;
+        let id = PeerId::from_bytes(id.try_into().map_err(|_| refused())?);
+        let signature = Signature::from_slice(signature).map_err(|_| refused())?;
+        if id.verify(&noise_message(static_key), &signature) {
+            Ok(id)
+        } else {
+            Err(refused())
+        }
+    }
+
+    /// Run the Noise handshake on `tcp` as the side that connected, returning the sealed stream
+    /// and the [PeerId] of the other side
+    async fn initiate(&self, tcp: &mut TcpStream) -> io::Result<(StatelessTransportState, PeerId)> {
+        let (mut state, credentials) = self.noise(true)?;
+        let (mut message, mut payload) = (vec![0; MAX_MESSAGE], vec![0; MAX_MESSAGE]);
+
+        // -> e
+        let len = state
+            .write_message(&[], &mut message)
+            .map_err(noise_error)?;
+        send(tcp, &message[..len]).await?;
+
+        // <- e, ee, s, es
+        let len = state
+            .read_message(&recv(tcp).await?, &mut payload)
+            .map_err(noise_error)?;
+        let remote = Self::verify(&state, &payload[..len])?;
+
+        // -> s, se
+        let len = state
+            .write_message(&credentials, &mut message)
+            .map_err(noise_error)?;
+        send(tcp, &message[..len]).await?;
+
+        let transport = state.into_stateless_transport_mode().map_err(noise_error)?;
+        Ok((transport, remote))
+    }
+
+    /// Run the Noise handshake on `tcp` as the side that accepted, returning the sealed stream
+    /// and the [PeerId] of the other side
+    async fn respond(&self, tcp: &mut TcpStream) -> io::Result<(StatelessTransportState, PeerId)> {
+        let (mut state, credentials) = self.noise(false)?;
+        let (mut message, mut payload) = (vec![0; MAX_MESSAGE], vec![0; MAX_MESSAGE]);
+
+        // -> e
+        state
+            .read_message(&recv(tcp).await?, &mut payload)
+            .map_err(noise_error)?;
+
+        // <- e, ee, s, es
+        let len = state
+            .write_message(&credentials, &mut message)
+            .map_err(noise_error)?;
+        send(tcp, &message[..len]).await?;
+
+        // -> s, se
+        let len = state
+            .read_message(&recv(tcp).await?, &mut payload)
+            .map_err(noise_error)?;
+        let remote = Self::verify(&state, &payload[..len])?;
+
+        let transport = state.into_stateless_transport_mode().map_err(noise_error)?;
+        Ok((transport, remote))
+    }
+
+    /// Wrap `tcp` into a [Connection], running the Noise handshake first if it is enabled and
+    /// checking membership of the network after
+    async fn establish(&self, mut tcp: TcpStream, initiator: bool) -> io::Result<Connection> {
+        let peer_addr = tcp.peer_addr()?;
+        let (stream, remote) = match self.security {
+            Security::Plaintext => (Stream::Plain(tcp), None),
+            Security::Noise => {
+                let handshake = async {
+                    if initiator {
+                        self.initiate(&mut tcp).await
+                    } else {
+                        self.respond(&mut tcp).await
+                    }
+                };
+                let (noise, remote) =
+                    timeout(HANDSHAKE_TIMEOUT, handshake).await.map_err(|_| {
+                        io::Error::new(ErrorKind::TimedOut, "noise handshake timed out")
+                    })??;
+                (Stream::Noise(seal(tcp, noise)), Some(remote))
+            }
+        };
+        let mut conn = Connection {
+            stream,
+            peer_addr,
+            remote,
+        };
+
+        if let Some(network) = &self.network {
+            timeout(HANDSHAKE_TIMEOUT, network.admit(&mut conn, initiator))
+                .await
+                .map_err(|_| io::Error::new(ErrorKind::TimedOut, "network check timed out"))??;
+        }
+        Ok(conn)
+    }
+
+    /// Connect to the node at `addr`, failing if it proves a different ID than `expected`
+    ///
+    /// `expected` is only checked over [Security::Noise], since plaintext connections prove
+    /// nothing.
+    pub async fn connect<Item, SinkItem>(
+        &self,
+        addr: SocketAddr,
+        expected: Option<PeerId>,
+    ) -> io::Result<Transport<Item, SinkItem>>
+    where
+        Item: for<'de> Deserialize<'de>,
+        SinkItem: Serialize,
+    {
+        let conn = self
+            .establish(TcpStream::connect(addr).await?, true)
+            .await?;
+        if let (Some(x), Some(remote)) = (expected, conn.remote) {
+            if x != remote {
+                return Err(io::Error::new(
+                    ErrorKind::PermissionDenied,
+                    format!("{addr} is {remote}, not {x}"),
+                ));
+            }
+        }
+        Ok(Transport::from((conn, Bincode::default())))
+    }
+
+    /// Listen on `bind`, handing out connections once they finish their handshake
+    ///
+    /// Connections failing the handshake are dropped. The listener stops accepting once the
+    /// returned [Listener] is dropped.
+    pub async fn listen<Item, SinkItem>(
+        &self,
+        bind: SocketAddr,
+    ) -> io::Result<Listener<Item, SinkItem>> {
+        let listener = TcpListener::bind(bind).await?;
+        let local_addr = listener.local_addr()?;
+        let (tx, incoming) = mpsc::unbounded_channel();
+
+        let endpoint = self.clone();
+        tokio::spawn(async move {
+            loop {
+                let tcp = tokio::select! {
+                    x = listener.accept() => match x {
+                        Ok((tcp, _)) => tcp,
+                        // Ignore accept errors.
+                        Err(_) => continue,
+                    },
+                    _ = tx.closed() => return,
+                };
+
+                // handshake in the background so a slow node doesn't hold up the others
+                let (endpoint, tx) = (endpoint.clone(), tx.clone());
+                tokio::spawn(async move {
+                    match endpoint.establish(tcp, false).await {
+                        Ok(conn) => {
+                            let _ = tx.send(conn);
+                        }
+                        Err(e) => println!("Dropped incoming connection: {e}"),
+                    }
+                });
+            }
+        });
+
+        Ok(Listener {
+            local_addr,
+            incoming,
+            rpc: PhantomData,
+        })
+    }
+}
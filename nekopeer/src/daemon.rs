@@ -0,0 +1,130 @@
+//! Headless mode serving [Control] requests on a Unix socket
+//!
+//! The peer keeps seeding while the daemon runs, and stops once it is told to [Control::shutdown]
+//! or receives `SIGINT` or `SIGTERM`.
+use std::{
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Result};
+use futures::prelude::*;
+use tarpc::{
+    context::Context,
+    serde_transport::unix,
+    server::{BaseChannel, Channel},
+    tokio_serde::formats::Bincode,
+};
+use tokio::{
+    fs,
+    net::UnixStream,
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+    sync::Notify,
+};
+
+use nekop2p::{Control, Hit, NodeStatus, PeerNode, Query, Status};
+
+use crate::{download_file, register_file};
+
+/// [Control] server for a [PeerNode]
+#[derive(Clone)]
+struct ControlServer {
+    node: PeerNode,
+    indexer: SocketAddr,
+    started: Instant,
+    shutdown: Arc<Notify>,
+}
+
+impl Control for ControlServer {
+    async fn register(self, _: Context, filename: String) -> Status {
+        register_file(&self.node, &filename).await
+    }
+
+    async fn download(self, _: Context, filename: String, swarm: bool) -> Status {
+        download_file(&self.node, &filename, swarm).await
+    }
+
+    async fn search(self, _: Context, query: Query, local: bool) -> Option<Vec<Hit>> {
+        match self.node.search(&query, local).await {
+            Ok(x) => Some(x),
+            Err(e) => {
+                println!("Failed to retrieve peers for {query}: {e}");
+                None
+            }
+        }
+    }
+
+    async fn status(self, _: Context) -> NodeStatus {
+        NodeStatus {
+            id: self.node.id(),
+            indexer: self.indexer,
+            dl_addr: self.node.dl_addr(),
+            share_dirs: self.node.share_root().dirs().to_vec(),
+            files: self.node.files().await,
+            uptime: Duration::from_secs(self.started.elapsed().as_secs()),
+        }
+    }
+
+    async fn shutdown(self, _: Context) {
+        println!("Shutting down on request");
+        self.shutdown.notify_one();
+    }
+}
+
+/// Make sure no other daemon is listening on `socket`, removing it if it was left behind by one
+/// that died
+pub async fn claim(socket: &Path) -> Result<()> {
+    if UnixStream::connect(socket).await.is_ok() {
+        bail!("a daemon is already listening on {0}", socket.display());
+    }
+    let _ = fs::remove_file(socket).await;
+    Ok(())
+}
+
+/// Serve [Control] requests for `node`, connected to `indexer`, on the Unix socket at `socket`
+/// (which has to be [claim]ed first) until told to shut down
+pub async fn run(node: PeerNode, indexer: SocketAddr, socket: &Path) -> Result<()> {
+    let listener = unix::listen(socket, Bincode::default).await?;
+    println!("Accepting control connections on {0}", socket.display());
+
+    let shutdown = Arc::new(Notify::new());
+    let server = ControlServer {
+        node,
+        indexer,
+        started: Instant::now(),
+        shutdown: Arc::clone(&shutdown),
+    };
+
+    // keeps running while the daemon winds down so the shutdown request gets its response
+    tokio::spawn(
+        listener
+            // Ignore accept errors.
+            .filter_map(|r| future::ready(r.ok()))
+            .map(BaseChannel::with_defaults)
+            .map(move |channel| {
+                channel
+                    .execute(server.clone().serve())
+                    .for_each(|response| async move {
+                        tokio::spawn(response);
+                    })
+            })
+            // Max 10 channels.
+            .buffer_unordered(10)
+            .for_each(|_| async {}),
+    );
+
+    let mut terminate = signal(SignalKind::terminate())?;
+    tokio::select! {
+        _ = shutdown.notified() => {}
+        x = signal::ctrl_c() => x?,
+        _ = terminate.recv() => {}
+    }
+
+    let _ = fs::remove_file(socket).await;
+    Ok(())
+}
@@ -1,35 +1,39 @@
-//! Simple binary wrapping the reference implementation of [PeerServer] in a
-//! [tarpc::serde_transport::tcp::connect]
+//! Simple binary wrapping the reference implementation of [PeerNode] in a command line interface
 //!
-//! Connects to [nekop2p::Indexer]s and [Peer]s using [IndexerClient] and [PeerClient]
-//! respectively.
+//! Connects to [nekop2p::Indexer]s using an [IndexerClient], while [PeerNode] handles sharing,
+//! serving and downloading files.
+mod daemon;
+mod watch;
+
 use std::{
+    collections::HashMap,
     io::{stdin, stdout, Write},
     net::SocketAddr,
-    time::Duration,
+    path::PathBuf,
+    process::ExitCode,
+    sync::Arc,
 };
 
-use anyhow::Result;
-use clap::Parser;
-use futures::prelude::*;
-use rand::seq::SliceRandom;
+use anyhow::{anyhow, Context, Result};
+use clap::{Parser, Subcommand};
 use serde::Deserialize;
-use tarpc::{
-    client, context,
-    serde_transport::tcp,
-    server::{BaseChannel, Channel},
-    tokio_serde::formats::Bincode,
-};
+use tarpc::client;
 use tokio::{fs, signal};
-use uuid::Uuid;
 
-use nekop2p::{IndexerClient, Metadata, Peer, PeerClient, PeerServer};
+use nekop2p::{
+    format_hits, Endpoint, IndexerClient, Keypair, NetworkKey, NodeError, NodeEvent, PeerId,
+    PeerNode, Query, Security, ShareRoot, Status, Verification, CONTROL_SOCKET,
+};
+use watch::watch_share;
 
 #[derive(Deserialize)]
 struct Config {
     /// indexer to bind to
     indexer: SocketAddr,
 
+    /// ID the indexer has to prove when [Config::encrypt] is set (default any)
+    indexer_id: Option<PeerId>,
+
     /// incoming peer connection [std::net::SocketAddr] to bind to
     dl_bind: SocketAddr,
 
@@ -38,12 +42,107 @@ struct Config {
 
     /// TTR of downloads (default 255)
     ttr: Option<u8>,
+
+    /// Directories to share files from and download into (default current directory)
+    share_dirs: Option<Vec<PathBuf>>,
+
+    /// Keep the index in sync with changes to the share directories (default true)
+    watch: Option<bool>,
+
+    /// Unix socket a daemon accepts [nekop2p::Control] connections on (default
+    /// [nekop2p::CONTROL_SOCKET])
+    control: Option<PathBuf>,
+
+    /// Directory the [Keypair] behind this peer's [nekop2p::PeerId] is kept in, as `peer.key`
+    /// (default current directory)
+    data_dir: Option<PathBuf>,
+
+    /// Encrypt and authenticate every connection with Noise (default false)
+    encrypt: Option<bool>,
+
+    /// Secret shared by the nodes of a private network, which this peer only talks within
+    /// (default any node)
+    network_secret: Option<String>,
+
+    /// Named groups of peer IDs that [Config::acl] rules can let in (default none)
+    groups: Option<HashMap<String, Vec<PeerId>>>,
+
+    /// Files and directories only some peers may download (default anyone may download anything)
+    acl: Option<Vec<AclRule>>,
+}
+
+/// Restricts a file or directory to a list of readers
+#[derive(Deserialize)]
+struct AclRule {
+    /// File or directory the rule applies to, such as one of [Config::share_dirs]
+    path: PathBuf,
+
+    /// IDs of peers and names of [Config::groups] allowed to download from it
+    readers: Vec<String>,
+}
+
+/// IDs of the peers `readers` names, looking up the members of `groups`
+fn expand_readers(
+    readers: &[String],
+    groups: &HashMap<String, Vec<PeerId>>,
+) -> Result<Vec<PeerId>> {
+    let mut ids = Vec::new();
+    for reader in readers {
+        match groups.get(reader) {
+            Some(x) => ids.extend_from_slice(x),
+            None => ids.push(
+                reader
+                    .parse()
+                    .map_err(|_| anyhow!("{reader} is neither a group nor a peer ID"))?,
+            ),
+        }
+    }
+    Ok(ids)
 }
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Args {
     config: String,
+
+    /// Run a single command and exit instead of entering the REPL
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// One-shot commands, each exiting with the [Status] of the operation
+#[derive(Subcommand)]
+enum Command {
+    /// Download a file from the network into the share directories
+    Get {
+        filename: String,
+
+        /// Download from every peer holding the file at once
+        #[arg(long)]
+        swarm: bool,
+    },
+
+    /// Register a file inside the share directories and seed it until interrupted
+    Share { filename: String },
+
+    /// Search the network for files matching a name or pattern
+    Search {
+        pattern: Query,
+
+        /// Only search the connected indexer instead of the whole network
+        #[arg(long)]
+        local: bool,
+
+        /// Only list holders that proved to their indexer that they have the file
+        #[arg(long)]
+        verified: bool,
+    },
+
+    /// Deregister a file shared by this peer
+    Unshare { filename: String },
+
+    /// Share and seed in the background, taking commands from nekoctl over the control socket
+    Daemon,
 }
 
 /// Given a `prompt` read a line from [stdout] and return it if it exists
@@ -64,357 +163,227 @@ fn print_help() {
     println!("Available CLI commands:");
     println!("register\tRegister file (or update file) to index");
     println!("download\tDownload file (or update file) from peer on index");
-    println!("search\t\tQuery peers on index with file");
+    println!("swarm\t\tDownload file (or update file) from all peers on index at once");
+    println!("search\t\tQuery peers on index with file or pattern");
     println!("deregister\tDeregister file on index");
-    println!("query\tQueries entire network for file");
+    println!("query\tQueries entire network for file or pattern");
     println!("?\t\tPrint this help screen");
     println!("exit\t\tQuit");
 }
 
-/// Read metadata from file
-async fn read_metadata(filename: &str) -> Result<Metadata> {
-    // get origin server and version from metadata
-    let metadata_text = fs::read_to_string(filename.to_owned() + ".meta").await?;
-    let metadata: Metadata = toml::from_str(metadata_text.as_str())?;
-    Ok(metadata)
+/// Print why a command failed, returning the matching [Status]
+fn report(action: &str, e: NodeError) -> Status {
+    println!("Failed to {action}: {e}");
+    (&e).into()
 }
 
-/// Write metadata to file
-async fn write_metadata(filename: &str, metadata: &Metadata) -> Result<()> {
-    // create metadata file
-    let metadata_text = toml::to_string_pretty(&metadata)?;
-    fs::write(filename.trim_end().to_owned() + ".meta", metadata_text).await?;
-    Ok(())
+/// Publish `filename` as a new version through `node`
+async fn register_file(node: &PeerNode, filename: &str) -> Status {
+    match node.share(filename).await {
+        Ok(_) => {
+            println!("Registered {filename} on index");
+            Status::Done
+        }
+        Err(e) => report(&format!("register {filename}"), e),
+    }
 }
 
-/// Check file validity after ttr
-async fn poll_file_validity(filename: String, metadata: Metadata) {
-    loop {
-        // sleep for ttr, then poll
-        tokio::time::sleep(Duration::from_secs(metadata.ttr.into())).await;
-
-        println!("Polling validity of {0}...", filename.trim_end());
-        let transport = match tcp::connect(metadata.origin_server, Bincode::default).await {
-            Ok(x) => {
-                println!("Connecting to peer {0}", metadata.origin_server);
-                x
-            }
-            Err(_) => {
-                println!(
-                    "Failed to download metadata for {0}, removing",
-                    filename.trim_end()
-                );
-                let _ = fs::remove_file(filename.trim_end()).await;
-                let _ = fs::remove_file(filename.trim_end().to_owned() + ".meta").await;
-                return;
-            }
-        };
+/// Register a filename that is prompted for through `node`
+async fn prompt_register(node: &PeerNode) {
+    let filename = input("Enter filename").unwrap();
+    register_file(node, filename.trim_end()).await;
+}
 
-        let peer = PeerClient::new(client::Config::default(), transport).spawn();
-        // then, get the updated file metadata
-        let new_metadata = match peer
-            .get_metadata(context::current(), filename.trim_end().to_owned())
-            .await
-        {
-            Ok(Some(x)) => x,
-            _ => {
-                println!(
-                    "Failed to download metadata for {0}, removing",
-                    filename.trim_end()
-                );
-                let _ = fs::remove_file(filename.trim_end()).await;
-                let _ = fs::remove_file(filename.trim_end().to_owned() + ".meta").await;
-                return;
-            }
-        };
+/// Print what happened to `event` of a [PeerNode]
+fn print_event(event: &NodeEvent) {
+    println!("{event}");
+}
 
-        if metadata != new_metadata {
-            // redownload needed
+/// Register every file inside the share directories of `node`
+async fn share_all(node: &PeerNode) {
+    match node.sync_all().await {
+        Ok(x) => {
+            for (filename, e) in &x.skipped {
+                println!("Not sharing {filename}: {e}");
+            }
             println!(
-                "Metadata changed for {0} between remote and local, removing",
-                filename.trim_end()
+                "Registered {0} files from the share directories",
+                x.registered.len()
             );
-            let _ = fs::remove_file(filename.trim_end()).await;
-            let _ = fs::remove_file(filename.trim_end().to_owned() + ".meta").await;
-            return;
         }
+        Err(e) => println!("Failed to register files from the share directories: {e}"),
     }
 }
 
-/// Given an [IndexerClient] register a filename that is prompted for
-async fn prompt_register(client: &IndexerClient, origin_server: SocketAddr, ttr: u8) {
-    let filename = input("Enter filename").unwrap();
-
-    // write/get metadata first
-    let metadata = match read_metadata(filename.trim_end()).await {
-        Ok(mut x) => {
-            x.version += 1; // increment version since we're updating this file
-            x
+/// Download `filename` through `node` without registering it
+async fn get_file(node: &PeerNode, filename: &str, swarm: bool) -> Status {
+    match node.download(filename, swarm).await {
+        Ok(_) => {
+            println!("Wrote contents to {filename}");
+            Status::Done
         }
-        Err(_) => {
-            // not found, make new metadata file instead
-            let metadata = Metadata {
-                origin_server, // this is the origin server!
-                version: 0,    // initial version is zero
-                ttr,           // we set the ttr
-            };
-            if write_metadata(filename.trim_end(), &metadata)
-                .await
-                .is_err()
-            {
-                println!("Failed to get metadata for {0}", filename.trim_end());
-                return;
-            }
-            metadata
-        }
-    };
-
-    // (try to) invalidate old versions
-    match client
-        .invalidate(
-            context::current(),
-            Uuid::new_v4(),
-            metadata.origin_server,
-            filename.trim_end().to_owned(),
-        )
-        .await
-    {
-        Ok(_) => println!(
-            "Sent invalidation message for older versions of {0}",
-            filename.trim_end()
-        ),
-        Err(_) => println!(
-            "Failed to invalidate older versions of {0}",
-            filename.trim_end()
-        ),
+        Err(e) => report(&format!("download {filename}"), e),
     }
+}
 
-    match client
-        .register(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(_) => println!("Registered {0} on index", filename.trim_end()),
-        Err(_) => println!("Failed to register {0}", filename.trim_end()),
+/// Download `filename` through `node` from a random peer (or from every peer at once if `swarm`
+/// is set) and register it with the [nekop2p::Indexer]
+async fn download_file(node: &PeerNode, filename: &str, swarm: bool) -> Status {
+    match node.fetch(filename, swarm).await {
+        Ok(_) => {
+            println!("Wrote contents to {filename}");
+            println!("Registered {filename} on index");
+            Status::Done
+        }
+        Err(e) => report(&format!("download {filename}"), e),
     }
 }
 
-/// Given an [IndexerClient] download a file that is prompted for from a random peer and register
-/// it with the [nekop2p::Indexer]
-async fn prompt_download(client: &IndexerClient, ttl: u8) {
+/// Download a file that is prompted for through `node`
+async fn prompt_download(node: &PeerNode, swarm: bool) {
     let filename = input("Enter filename").unwrap();
+    download_file(node, filename.trim_end(), swarm).await;
+}
 
-    let results = match client
-        .query(
-            context::current(),
-            Uuid::new_v4(),
-            filename.trim_end().to_owned(),
-            ttl,
-        )
-        .await
-    {
-        Ok(x) => {
-            println!("Querying peers for {0}", filename.trim_end());
-            x
-        }
-        Err(_) => {
-            println!("Failed to retrieve peers for {0}", filename.trim_end());
-            return;
-        }
-    };
-
-    // try to download file
-    let peer = match results.choose(&mut rand::thread_rng()) {
-        Some(x) => {
-            println!("Selected peer {x}");
-            x
+/// Prompt for a [Query], printing why if it can't be parsed
+fn input_query() -> Option<Query> {
+    let text = input("Enter filename or pattern")?;
+    match text.trim_end().parse() {
+        Ok(x) => Some(x),
+        Err(e) => {
+            println!("Invalid query {0}: {e}", text.trim_end());
+            None
         }
-        None => {
-            println!("No peers to download {0} from", filename.trim_end());
-            return;
-        }
-    };
+    }
+}
 
-    let transport = match tcp::connect(peer, Bincode::default).await {
+/// Search the whole network (or only the index if `local` is set) through `node` for files
+/// matching `query` and print the results, leaving out unverified holders if `verified` is set
+async fn search_files(node: &PeerNode, query: Query, local: bool, verified: bool) -> Status {
+    let mut results = match node.search(&query, local).await {
         Ok(x) => {
-            println!("Connecting to peer {0}", peer);
+            let scope = if local { "peers" } else { "network" };
+            println!("Querying {scope} for {query}");
             x
         }
-        Err(_) => {
-            println!("Failed to connect to peer {0}", peer);
-            return;
-        }
-    };
-
-    let peer = PeerClient::new(client::Config::default(), transport).spawn();
-    let contents = match peer
-        .download_file(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(Some(x)) => {
-            println!("Downloading {0}...", filename.trim_end());
-            x
-        }
-        Ok(None) | Err(_) => {
-            println!("Failed to download {0}", filename.trim_end());
-            return;
-        }
+        Err(e) => return report(&format!("retrieve peers for {query}"), e),
     };
+    if verified {
+        results.retain(|x| x.verification == Verification::Verified);
+    }
 
-    match fs::write(filename.trim_end(), contents).await {
-        Ok(_) => println!("Writing contents to {0}...", filename.trim_end()),
-        Err(_) => {
-            println!("Failed to write to {0}", filename.trim_end());
-            return;
-        }
+    // print out results
+    if results.is_empty() {
+        println!("No files matching {query}");
+        return Status::NotFound;
     }
+    print!("{0}", format_hits(&results));
+    Status::Done
+}
 
-    // then, get the file metadata
-    let metadata = match peer
-        .get_metadata(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(Some(x)) => {
-            println!("Downloading metadata for {0}...", filename.trim_end());
-            x
-        }
-        _ => {
-            println!("Failed to download metadata for {0}", filename.trim_end());
-            let _ = fs::remove_file(filename.trim_end()).await;
-            return;
-        }
-    };
-    // create metadata file
-    match write_metadata(filename.trim_end(), &metadata).await {
-        Ok(_) => println!("Wrote metadata for {0}", filename.trim_end()),
-        Err(_) => println!("Failed to write metadata for {0}", filename.trim_end()),
+/// Search for a filename or pattern that is prompted for through `node`
+async fn prompt_search(node: &PeerNode, local: bool) {
+    if let Some(query) = input_query() {
+        search_files(node, query, local, false).await;
     }
+}
 
-    // spawn poll system
-    tokio::spawn(poll_file_validity(filename.trim_end().to_owned(), metadata));
-
-    match client
-        .register(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(_) => println!("Registered {0} on index", filename.trim_end()),
-        Err(_) => {
-            println!("Failed to register {0}", filename.trim_end());
-            let _ = fs::remove_file(filename.trim_end()).await;
-            let _ = fs::remove_file(filename.trim_end().to_owned() + ".meta").await;
+/// Deregister `filename` through `node`
+async fn deregister_file(node: &PeerNode, filename: &str) -> Status {
+    match node.unshare(filename).await {
+        Ok(_) => {
+            println!("Deregistered {filename} on index");
+            Status::Done
         }
+        Err(e) => report(&format!("deregister {filename}"), e),
     }
 }
 
-/// Given an [IndexerClient] queries all peers for a filename that is prompted for
-async fn prompt_search(client: &IndexerClient) {
+/// Deregister a filename that is prompted for through `node`
+async fn prompt_deregister(node: &PeerNode) {
     let filename = input("Enter filename").unwrap();
-
-    let results = match client
-        .search(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(x) => {
-            println!("Querying peers for {0}", filename.trim_end());
-            x
-        }
-        Err(_) => {
-            println!("Failed to retrieve peers for {0}", filename.trim_end());
-            return;
-        }
-    };
-
-    // print out results
-    results.iter().for_each(|r| println!("{}", r));
+    deregister_file(node, filename.trim_end()).await;
 }
 
-/// Given an [IndexerClient] queries the network for a filename that is prompted for
-async fn prompt_query(client: &IndexerClient, ttl: u8) {
-    let filename = input("Enter filename").unwrap();
+/// Serve `share` on `dl_bind` through `endpoint` and register `filename`, seeding it until
+/// interrupted with [signal::ctrl_c]
+async fn seed(
+    client: &IndexerClient,
+    share: &Arc<ShareRoot>,
+    endpoint: &Endpoint,
+    dl_bind: SocketAddr,
+    ttl: u8,
+    ttr: u8,
+    filename: &str,
+) -> Result<Status> {
+    // make sure there is something to seed before taking the port
+    if share.resolve(filename).is_none() {
+        return Ok(report(
+            &format!("register {filename}"),
+            NodeError::NotShared(filename.to_owned()),
+        ));
+    }
 
-    let results = match client
-        .query(
-            context::current(),
-            Uuid::new_v4(),
-            filename.trim_end().to_owned(),
-            ttl,
-        )
-        .await
-    {
-        Ok(x) => {
-            println!("Querying network for {0}", filename.trim_end());
-            x
-        }
-        Err(_) => {
-            println!("Failed to retrieve peers for {0}", filename.trim_end());
-            return;
+    let node = PeerNode::listen(client, share, endpoint, dl_bind, ttl, ttr)
+        .await?
+        .on_event(print_event);
+    let status = register_file(&node, filename).await;
+    if status == Status::Done {
+        println!(
+            "Seeding {filename} on {0} until interrupted...",
+            node.dl_addr()
+        );
+        let heartbeat = node.clone().keep_alive(Some(filename.to_owned()));
+        tokio::select! {
+            x = signal::ctrl_c() => x?,
+            _ = heartbeat => {}
         }
-    };
+    }
 
-    // print out results
-    results.iter().for_each(|r| println!("{}", r));
+    // ensure the client registrations are cleared
+    node.disconnect().await?;
+    Ok(status)
 }
 
-/// Given an [IndexerClient] deregisters a filename that is prompted for
-async fn prompt_deregister(client: &IndexerClient) {
-    let filename = input("Enter filename").unwrap();
-
-    match client
-        .deregister(context::current(), filename.trim_end().to_owned())
-        .await
-    {
-        Ok(_) => println!("Deregistered {0} on index", filename.trim_end()),
-        Err(_) => println!("Failed to deregister {0}", filename.trim_end()),
+/// Starts a [PeerNode] through `endpoint` for `share` on [Config::dl_bind] and shares its
+/// contents, watching for changes if [Config::watch] is set and keeping the lease alive
+async fn start(
+    client: &IndexerClient,
+    share: &Arc<ShareRoot>,
+    endpoint: &Endpoint,
+    config: &Config,
+    ttl: u8,
+    ttr: u8,
+) -> Result<PeerNode> {
+    let node = PeerNode::listen(client, share, endpoint, config.dl_bind, ttl, ttr)
+        .await?
+        .on_event(print_event);
+
+    share_all(&node).await;
+    if config.watch.unwrap_or(true) {
+        let node = node.clone();
+        tokio::spawn(async move {
+            if let Err(e) = watch_share(node).await {
+                println!("Failed to watch share directories: {e}");
+            }
+        });
     }
-}
-
-/// Starts a [PeerServer] on [Args::dl_host] with [Args::dl_port] and connects to an
-/// [nekop2p::IndexerServer] on [Args::indexer]. Afterwards, the client will enter a REPL with
-/// [signal::ctrl_c] indicating when commands should be read.
-#[tokio::main]
-async fn main() -> Result<()> {
-    let args = Args::parse();
-    let config: Config = toml::from_str(
-        &fs::read_to_string(args.config)
-            .await
-            .expect("missing config file"),
-    )
-    .expect("failed to parse config file");
+    tokio::spawn(node.clone().keep_alive(None));
 
-    println!("Welcome to nekop2p! (peer client)");
-    println!("Press Ctrl-C to enter commands...");
-    println!("Connecting to indexer on {0}", config.indexer);
-    println!("Accepting inbound connections on {0}", config.dl_bind);
+    Ok(node)
+}
 
-    let transport = tcp::connect(config.indexer, Bincode::default);
-    let mut listener = tcp::listen(config.dl_bind, Bincode::default).await?;
-    listener.config_mut().max_frame_length(usize::MAX); // allow large frames
-
-    let ttl = config.ttl.unwrap_or(1);
-    let ttr = config.ttr.unwrap_or(255);
-    let origin_server = listener.local_addr();
-    let port = origin_server.port(); // get port (in-case dl_port = 0)
-
-    tokio::spawn(
-        listener
-            // Ignore accept errors.
-            .filter_map(|r| future::ready(r.ok()))
-            // Establish serve channel
-            .map(BaseChannel::with_defaults)
-            .map(|channel| {
-                let server = PeerServer::new(channel.transport().peer_addr().unwrap());
-                channel
-                    .execute(server.serve())
-                    .for_each(|response| async move {
-                        tokio::spawn(response);
-                    })
-            })
-            // Max 10 channels.
-            .buffer_unordered(10)
-            .for_each(|_| async {}),
-    );
-
-    let client = IndexerClient::new(client::Config::default(), transport.await?).spawn();
-    client.set_port(context::current(), port).await?;
+/// Starts sharing `share` and enters a REPL with [signal::ctrl_c] indicating when commands should
+/// be read
+async fn repl(
+    client: &IndexerClient,
+    share: &Arc<ShareRoot>,
+    endpoint: &Endpoint,
+    config: &Config,
+    ttl: u8,
+    ttr: u8,
+) -> Result<()> {
+    let node = start(client, share, endpoint, config, ttl, ttr).await?;
 
     loop {
         // wait for SIGINT
@@ -424,11 +393,12 @@ async fn main() -> Result<()> {
         let input = input("\nEnter Command ('?' for help)").unwrap();
 
         match input.as_str().trim_end() {
-            "register" => prompt_register(&client, origin_server, ttr).await,
-            "download" => prompt_download(&client, ttl).await,
-            "search" => prompt_search(&client).await,
-            "deregister" => prompt_deregister(&client).await,
-            "query" => prompt_query(&client, ttl).await,
+            "register" => prompt_register(&node).await,
+            "download" => prompt_download(&node, false).await,
+            "swarm" => prompt_download(&node, true).await,
+            "search" => prompt_search(&node, true).await,
+            "deregister" => prompt_deregister(&node).await,
+            "query" => prompt_search(&node, false).await,
             "?" => print_help(),
             "exit" => break,
             _ => println!("Unknown command"),
@@ -436,7 +406,135 @@ async fn main() -> Result<()> {
     }
 
     // ensure the client registrations are cleared
-    client.disconnect_peer(context::current()).await?;
+    node.disconnect().await?;
 
     Ok(())
 }
+
+/// Connects to an [nekop2p::IndexerServer] on [Config::indexer] and either runs a single
+/// [Command], exiting with its [Status], or enters the REPL
+#[tokio::main]
+async fn main() -> ExitCode {
+    match run(Args::parse()).await {
+        Ok(x) => x.into(),
+        Err(e) => {
+            println!("{e}");
+            Status::Failed.into()
+        }
+    }
+}
+
+/// Runs the [Command] in `args`, or the REPL if there is none
+async fn run(args: Args) -> Result<Status> {
+    let config: Config = toml::from_str(
+        &fs::read_to_string(args.config)
+            .await
+            .expect("missing config file"),
+    )
+    .expect("failed to parse config file");
+
+    if args.command.is_none() {
+        println!("Welcome to nekop2p! (peer client)");
+        println!("Press Ctrl-C to enter commands...");
+        println!("Connecting to indexer on {0}", config.indexer);
+        println!("Accepting inbound connections on {0}", config.dl_bind);
+    }
+
+    let ttl = config.ttl.unwrap_or(1);
+    let ttr = config.ttr.unwrap_or(255);
+    let key = config
+        .data_dir
+        .clone()
+        .unwrap_or_else(|| PathBuf::from("."))
+        .join("peer.key");
+    let keypair = Arc::new(Keypair::load_or_generate(&key)?);
+
+    // the key may well live inside a share directory, so make sure it is never shared
+    let mut share = ShareRoot::new(
+        config
+            .share_dirs
+            .clone()
+            .unwrap_or_else(|| vec![PathBuf::from(".")]),
+    )
+    .and_then(|x| x.hide(&key))
+    .expect("failed to open share directories");
+    let groups = config.groups.clone().unwrap_or_default();
+    for rule in config.acl.iter().flatten() {
+        let readers = expand_readers(&rule.readers, &groups)?;
+        share = share
+            .restrict(&rule.path, readers)
+            .with_context(|| format!("failed to restrict {0}", rule.path.display()))?;
+    }
+    let share = Arc::new(share);
+    if args.command.is_none() {
+        println!("Peer ID {0}", keypair.id());
+    }
+
+    let security = match config.encrypt {
+        Some(true) => Security::Noise,
+        _ => Security::Plaintext,
+    };
+    let mut endpoint = Endpoint::new(&keypair, security);
+    if let Some(secret) = &config.network_secret {
+        endpoint = endpoint.network(NetworkKey::from_secret(secret));
+    }
+    let transport = endpoint.connect(config.indexer, config.indexer_id).await?;
+    let client = IndexerClient::new(client::Config::default(), transport).spawn();
+
+    // one-shot commands act on behalf of the peer holding the configured key
+    let node =
+        PeerNode::new(&client, &share, &endpoint, config.dl_bind, ttl, ttr).on_event(print_event);
+
+    // commands that start serving introduce themselves once they listen
+    if !matches!(
+        args.command,
+        None | Some(Command::Share { .. } | Command::Daemon)
+    ) {
+        node.handshake().await?;
+    }
+    let status = match args.command {
+        Some(Command::Get { filename, swarm }) => get_file(&node, &filename, swarm).await,
+        Some(Command::Share { filename }) => {
+            seed(
+                &client,
+                &share,
+                &endpoint,
+                config.dl_bind,
+                ttl,
+                ttr,
+                &filename,
+            )
+            .await?
+        }
+        Some(Command::Search {
+            pattern,
+            local,
+            verified,
+        }) => search_files(&node, pattern, local, verified).await,
+        Some(Command::Unshare { filename }) => {
+            // the index drops the file for every connection proving the same key
+            deregister_file(&node, &filename).await
+        }
+        Some(Command::Daemon) => {
+            let control = config
+                .control
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(CONTROL_SOCKET));
+            daemon::claim(&control).await?;
+
+            let node = start(&client, &share, &endpoint, &config, ttl, ttr).await?;
+            println!("Accepting inbound connections on {0}", node.dl_addr());
+            daemon::run(node.clone(), config.indexer, &control).await?;
+
+            // ensure the client registrations are cleared
+            node.disconnect().await?;
+            Status::Done
+        }
+        None => {
+            repl(&client, &share, &endpoint, &config, ttl, ttr).await?;
+            Status::Done
+        }
+    };
+
+    Ok(status)
+}
@@ -0,0 +1,90 @@
+//! Background watcher keeping the [nekop2p::Indexer] in sync with the share directories
+//!
+//! A file is only looked at once it has been left alone for [DEBOUNCE], so a burst of writes to
+//! it produces a single new version.
+use std::{path::PathBuf, time::Duration};
+
+use delay_map::HashSetDelay;
+use futures::prelude::*;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+use tokio::sync::mpsc;
+
+use nekop2p::PeerNode;
+
+/// How long a file has to stay untouched before changes to it are shared
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Watch the share directories of `node`, registering new files, publishing new versions of
+/// changed files this peer is the origin of, and deregistering deleted files
+pub async fn watch_share(node: PeerNode) -> notify::Result<()> {
+    let (tx, mut rx) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        if let Ok(event) = event {
+            if !matches!(event.kind, EventKind::Access(_)) {
+                event.paths.into_iter().for_each(|p| {
+                    let _ = tx.send(p);
+                });
+            }
+        }
+    })?;
+    for dir in node.share_root().dirs() {
+        watcher.watch(dir, RecursiveMode::Recursive)?;
+    }
+
+    // every event on a path pushes its deadline back
+    let mut pending = HashSetDelay::new(DEBOUNCE);
+    loop {
+        tokio::select! {
+            Some(path) = rx.recv() => pending.insert(path),
+            Some(Ok(path)) = pending.next(), if !pending.is_empty() => {
+                let mut settled = vec![path];
+                while let Some(Some(Ok(path))) = pending.next().now_or_never() {
+                    settled.push(path);
+                }
+                sync(&node, settled).await;
+            }
+            else => return Ok(()),
+        }
+    }
+}
+
+/// Register each of the settled `paths` that still exists and deregister the rest
+async fn sync(node: &PeerNode, paths: Vec<PathBuf>) {
+    let mut existing = Vec::new();
+    let mut removed = Vec::new();
+    for path in paths {
+        let Some(filename) = node.filename_of(&path) else {
+            continue;
+        };
+
+        if node.share_root().resolve(&filename).is_some() {
+            existing.push(filename);
+        } else if !path.exists() {
+            // directories don't resolve either, but are still there
+            removed.push(filename);
+        }
+    }
+
+    if !existing.is_empty() {
+        match node.sync(&existing).await {
+            Ok(x) => {
+                for (filename, e) in &x.skipped {
+                    println!("Not sharing {filename}: {e}");
+                }
+                if !x.registered.is_empty() {
+                    let filenames: Vec<_> = x.registered.into_iter().map(|x| x.filename).collect();
+                    println!("Registered {0} on index", filenames.join(", "));
+                }
+            }
+            Err(e) => println!("Failed to register {0}: {e}", existing.join(", ")),
+        }
+    }
+
+    if !removed.is_empty() {
+        let filenames = removed.join(", ");
+        match node.unshare_many(removed).await {
+            Ok(_) => println!("Deregistered {filenames} on index"),
+            Err(e) => println!("Failed to deregister {filenames}: {e}"),
+        }
+    }
+}